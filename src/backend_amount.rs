@@ -0,0 +1,602 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::ops::{Add, Mul, Neg, Sub};
+
+use crate::backend_format::Amount;
+
+/// Each limb holds 9 decimal digits
+const LIMB_BASE: u64 = 1_000_000_000;
+const LIMB_DIGITS: usize = 9;
+
+/// An exact, arbitrary precision decimal number: `magnitude * 10^-scale`
+#[derive(Clone, Debug, Default)]
+pub struct Decimal {
+    negative: bool,
+    /// little endian, base 10^9; no trailing (ie most significant) zero limbs
+    magnitude: Vec<u32>,
+    scale: u32,
+}
+
+/// How a quantity was written in the source, so that computed quantities can be
+/// written the same way.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QuantityStyle {
+    pub decimal_mark: char,
+    pub thousands_separator: Option<char>,
+}
+
+impl Default for QuantityStyle {
+    fn default() -> Self {
+        Self {
+            decimal_mark: '.',
+            thousands_separator: None,
+        }
+    }
+}
+
+impl Decimal {
+    /// Parse a quantity as written in a journal, eg `1,234.56`, `-10`,
+    /// `1.234,56` or `1,5`.
+    ///
+    /// If both `.` and `,` are present, the last one is the decimal mark. If only
+    /// `,` is present, it's a decimal mark if it appears once and is not followed
+    /// by exactly 3 digits. If only `.` is present, it's a thousands separator if
+    /// it appears more than once.
+    pub fn parse(quantity: &str) -> Option<(Self, QuantityStyle)> {
+        let quantity = quantity.trim();
+        let (negative, quantity) = match quantity.strip_prefix('-') {
+            Some(quantity) => (true, quantity.trim_start()),
+            None => (false, quantity.strip_prefix('+').unwrap_or(quantity)),
+        };
+
+        let commas = quantity.matches(',').count();
+        let periods = quantity.matches('.').count();
+        let style = match (commas, periods) {
+            (0, 0) => QuantityStyle::default(),
+            (0, 1) => QuantityStyle::default(),
+            (0, _) => QuantityStyle {
+                decimal_mark: ',',
+                thousands_separator: Some('.'),
+            },
+            (1, 0) if quantity.rsplit(',').next().map(str::len) != Some(3) => QuantityStyle {
+                decimal_mark: ',',
+                thousands_separator: None,
+            },
+            (_, 0) => QuantityStyle {
+                decimal_mark: '.',
+                thousands_separator: Some(','),
+            },
+            (_, _) if quantity.rfind(',') > quantity.rfind('.') => QuantityStyle {
+                decimal_mark: ',',
+                thousands_separator: Some('.'),
+            },
+            (_, _) => QuantityStyle {
+                decimal_mark: '.',
+                thousands_separator: Some(','),
+            },
+        };
+
+        let quantity = match style.thousands_separator {
+            Some(separator) => quantity.replace(separator, ""),
+            None => quantity.to_string(),
+        };
+        let (whole, fraction) = quantity
+            .split_once(style.decimal_mark)
+            .unwrap_or((&quantity, ""));
+        if (whole.is_empty() && fraction.is_empty())
+            || fraction.contains(style.decimal_mark)
+            || !whole
+                .chars()
+                .chain(fraction.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+
+        let decimal = Self {
+            negative,
+            magnitude: magnitude_from_digits(&format!("{whole}{fraction}")),
+            scale: fraction.len() as u32,
+        }
+        .normalized();
+
+        Some((decimal, style))
+    }
+
+    pub fn zero() -> Self {
+        Self::default()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn abs(&self) -> Self {
+        Self {
+            negative: false,
+            ..self.clone()
+        }
+    }
+
+    /// The number of digits after the decimal mark
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// Write this number as it would be written in a journal, with the given
+    /// decimal mark and thousands separator.
+    pub fn to_string_with_style(&self, style: &QuantityStyle) -> String {
+        let digits = self.digits();
+        let (whole, fraction) = digits.split_at(digits.len() - self.scale as usize);
+
+        let mut s = String::new();
+        if self.negative {
+            s.push('-');
+        }
+        for (i, c) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i) % 3 == 0 {
+                if let Some(separator) = style.thousands_separator {
+                    s.push(separator);
+                }
+            }
+            s.push(c);
+        }
+        if !fraction.is_empty() {
+            s.push(style.decimal_mark);
+            s.push_str(fraction);
+        }
+
+        s
+    }
+
+    /// All digits of the magnitude, padded so that there is at least one digit
+    /// before the decimal mark.
+    fn digits(&self) -> String {
+        let mut digits = match self.magnitude.split_last() {
+            Some((most_significant, rest)) => {
+                rest.iter()
+                    .rev()
+                    .fold(most_significant.to_string(), |mut digits, limb| {
+                        digits.push_str(&format!("{limb:0>LIMB_DIGITS$}"));
+                        digits
+                    })
+            }
+            None => String::new(),
+        };
+        let min_len = self.scale as usize + 1;
+        if digits.len() < min_len {
+            digits = format!("{digits:0>min_len$}");
+        }
+        digits
+    }
+
+    /// Multiply the magnitude by 10^(scale - self.scale), so that both numbers
+    /// can be compared/added digit for digit. `scale` must be >= `self.scale`.
+    fn rescaled(&self, scale: u32) -> Self {
+        let mut magnitude = self.magnitude.clone();
+        let shift = scale.saturating_sub(self.scale) as usize;
+        magnitude_mul_small(&mut magnitude, 10u32.pow((shift % LIMB_DIGITS) as u32));
+        if !magnitude.is_empty() {
+            magnitude.splice(0..0, std::iter::repeat_n(0, shift / LIMB_DIGITS));
+        }
+
+        Self {
+            negative: self.negative,
+            magnitude,
+            scale: self.scale.max(scale),
+        }
+    }
+
+    fn normalized(mut self) -> Self {
+        while self.magnitude.last() == Some(&0) {
+            self.magnitude.pop();
+        }
+        if self.magnitude.is_empty() {
+            self.negative = false;
+        }
+        self
+    }
+}
+
+impl Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.to_string_with_style(&QuantityStyle::default())
+        )
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let scale = self.scale.max(other.scale);
+        let (a, b) = (self.rescaled(scale), other.rescaled(scale));
+
+        match (a.negative, b.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => magnitude_cmp(&a.magnitude, &b.magnitude),
+            (true, true) => magnitude_cmp(&b.magnitude, &a.magnitude),
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl Neg for Decimal {
+    type Output = Decimal;
+
+    fn neg(self) -> Self::Output {
+        Self {
+            negative: !self.negative,
+            ..self
+        }
+        .normalized()
+    }
+}
+
+impl Add for &Decimal {
+    type Output = Decimal;
+
+    fn add(self, other: Self) -> Self::Output {
+        let scale = self.scale.max(other.scale);
+        let (a, b) = (self.rescaled(scale), other.rescaled(scale));
+
+        let (negative, magnitude) = if a.negative == b.negative {
+            (a.negative, magnitude_add(&a.magnitude, &b.magnitude))
+        } else {
+            match magnitude_cmp(&a.magnitude, &b.magnitude) {
+                Ordering::Less => (b.negative, magnitude_sub(&b.magnitude, &a.magnitude)),
+                Ordering::Equal | Ordering::Greater => {
+                    (a.negative, magnitude_sub(&a.magnitude, &b.magnitude))
+                }
+            }
+        };
+
+        Decimal {
+            negative,
+            magnitude,
+            scale,
+        }
+        .normalized()
+    }
+}
+
+impl Sub for &Decimal {
+    type Output = Decimal;
+
+    fn sub(self, other: Self) -> Self::Output {
+        self + &(-other.clone())
+    }
+}
+
+impl Mul for &Decimal {
+    type Output = Decimal;
+
+    fn mul(self, other: Self) -> Self::Output {
+        Decimal {
+            negative: self.negative != other.negative,
+            magnitude: magnitude_mul(&self.magnitude, &other.magnitude),
+            scale: self.scale + other.scale,
+        }
+        .normalized()
+    }
+}
+
+fn magnitude_from_digits(digits: &str) -> Vec<u32> {
+    let digits = digits.trim_start_matches('0').as_bytes();
+    digits
+        .rchunks(LIMB_DIGITS)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .fold(0u32, |limb, digit| limb * 10 + (digit - b'0') as u32)
+        })
+        .collect()
+}
+
+fn magnitude_cmp(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn magnitude_add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut sum = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0;
+    for i in 0..a.len().max(b.len()) {
+        let limb = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        sum.push((limb % LIMB_BASE) as u32);
+        carry = limb / LIMB_BASE;
+    }
+    if carry > 0 {
+        sum.push(carry as u32);
+    }
+    sum
+}
+
+/// `a - b`, where `a >= b`
+fn magnitude_sub(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (i, limb) in a.iter().enumerate() {
+        let subtrahend = *b.get(i).unwrap_or(&0) as i64 + borrow;
+        let mut limb = *limb as i64 - subtrahend;
+        borrow = 0;
+        if limb < 0 {
+            limb += LIMB_BASE as i64;
+            borrow = 1;
+        }
+        difference.push(limb as u32);
+    }
+    difference
+}
+
+fn magnitude_mul(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+
+    let mut product = vec![0u64; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0;
+        for (j, y) in b.iter().enumerate() {
+            let limb = product[i + j] + *x as u64 * *y as u64 + carry;
+            product[i + j] = limb % LIMB_BASE;
+            carry = limb / LIMB_BASE;
+        }
+        product[i + b.len()] += carry;
+    }
+    product.into_iter().map(|limb| limb as u32).collect()
+}
+
+fn magnitude_mul_small(a: &mut Vec<u32>, multiplier: u32) {
+    let mut carry = 0;
+    for limb in a.iter_mut() {
+        let product = *limb as u64 * multiplier as u64 + carry;
+        *limb = (product % LIMB_BASE) as u32;
+        carry = product / LIMB_BASE;
+    }
+    if carry > 0 {
+        a.push(carry as u32);
+    }
+}
+
+/// A sum of amounts, kept separately per commodity
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Balance {
+    amounts: BTreeMap<Option<String>, Amount>,
+}
+
+impl Balance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an amount to the balance. Amounts w/o a value (eg that failed to
+    /// parse) are ignored.
+    pub fn add(&mut self, amount: &Amount) {
+        let Some(ref value) = amount.value else {
+            return;
+        };
+
+        match self.amounts.get_mut(&amount.commodity) {
+            Some(total) => {
+                let sum = total
+                    .value
+                    .as_ref()
+                    .map_or_else(|| value.clone(), |v| v + value);
+                *total = total.with_value(sum);
+            }
+            None => {
+                self.amounts
+                    .insert(amount.commodity.clone(), amount.with_value(value.clone()));
+            }
+        }
+    }
+
+    pub fn add_balance(&mut self, other: &Balance) {
+        for amount in other.amounts.values() {
+            self.add(amount);
+        }
+    }
+
+    /// The value of the commodity in this balance, zero if there is none
+    pub fn value(&self, commodity: &Option<String>) -> Decimal {
+        self.amounts
+            .get(commodity)
+            .and_then(|amount| amount.value.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn negated(&self) -> Self {
+        Self {
+            amounts: self
+                .amounts
+                .iter()
+                .map(|(commodity, amount)| {
+                    let value = amount.value.clone().unwrap_or_default();
+                    (commodity.clone(), amount.with_value(-value))
+                })
+                .collect(),
+        }
+    }
+
+    /// The non-zero amounts in this balance, one per commodity
+    pub fn amounts(&self) -> impl Iterator<Item = &Amount> {
+        self.amounts
+            .values()
+            .filter(|amount| amount.value.as_ref().is_some_and(|v| !v.is_zero()))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.amounts().next().is_none()
+    }
+}
+
+impl Display for Balance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+
+        let amounts = self
+            .amounts()
+            .map(|amount| amount.to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{}", amounts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decimal(quantity: &str) -> Decimal {
+        match Decimal::parse(quantity) {
+            Some((decimal, _)) => decimal,
+            None => panic!("unable to parse {quantity}"),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = vec![
+            "1",
+            "-1",
+            "1.5",
+            "1,234.56",
+            "1.234,56",
+            "1,5",
+            "1.234.567",
+            "0.10",
+            "000.5",
+            "12/3",
+            "",
+        ]
+        .into_iter()
+        .map(|q| (q, Decimal::parse(q).map(|(d, _)| d.to_string())))
+        .collect::<Vec<_>>();
+
+        insta::assert_debug_snapshot!(parsed,
+        @r#"
+        [
+            (
+                "1",
+                Some(
+                    "1",
+                ),
+            ),
+            (
+                "-1",
+                Some(
+                    "-1",
+                ),
+            ),
+            (
+                "1.5",
+                Some(
+                    "1.5",
+                ),
+            ),
+            (
+                "1,234.56",
+                Some(
+                    "1234.56",
+                ),
+            ),
+            (
+                "1.234,56",
+                Some(
+                    "1234.56",
+                ),
+            ),
+            (
+                "1,5",
+                Some(
+                    "1.5",
+                ),
+            ),
+            (
+                "1.234.567",
+                Some(
+                    "1234567",
+                ),
+            ),
+            (
+                "0.10",
+                Some(
+                    "0.10",
+                ),
+            ),
+            (
+                "000.5",
+                Some(
+                    "0.5",
+                ),
+            ),
+            (
+                "12/3",
+                None,
+            ),
+            (
+                "",
+                None,
+            ),
+        ]
+        "#
+        );
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!((&decimal("1.10") + &decimal("2.2")).to_string(), "3.30");
+        assert_eq!((&decimal("1.10") - &decimal("2.2")).to_string(), "-1.10");
+        assert_eq!((&decimal("-10") * &decimal("2.40")).to_string(), "-24.00");
+        assert_eq!((&decimal("10.00") - &decimal("10")).to_string(), "0.00");
+        assert!((&decimal("10.00") - &decimal("10")).is_zero());
+        assert_eq!(decimal("1.0"), decimal("1.000"));
+        assert!(decimal("-2") < decimal("1"));
+
+        // well beyond the range of i128 or f64
+        let big = decimal("123456789012345678901234567890123456789012.000000001");
+        assert_eq!(
+            (&big + &big).to_string(),
+            "246913578024691357802469135780246913578024.000000002"
+        );
+        assert_eq!(
+            (&big * &decimal("-1000")).to_string(),
+            "-123456789012345678901234567890123456789012000.000001000"
+        );
+        assert!((&big - &big).is_zero());
+    }
+
+    #[test]
+    fn test_style() {
+        let (d, style) = Decimal::parse("1.234,5").unwrap_or_default();
+        let d = &d * &decimal("-1000");
+        insta::assert_snapshot!(d.to_string_with_style(&style), @"-1.234.500,0");
+
+        let (d, style) = Decimal::parse("1,000.00").unwrap_or_default();
+        let d = &d + &decimal("0.5");
+        insta::assert_snapshot!(d.to_string_with_style(&style), @"1,000.50");
+    }
+}
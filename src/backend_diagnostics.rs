@@ -0,0 +1,321 @@
+use anyhow::Result;
+use tower_lsp::lsp_types::Range as LspRange;
+use tower_lsp::lsp_types::*;
+use tree_sitter::{Node, Tree};
+
+use crate::backend_amount::Balance;
+use crate::backend_format::{self, JournalItem, PlainXact};
+
+/// Check that the postings of every plain transaction sum to zero, per
+/// commodity.
+pub fn unbalanced_transactions(tree: &Tree, content: &str) -> Result<Vec<Diagnostic>> {
+    Ok(backend_format::parse_tree(tree, content)?
+        .iter()
+        .filter_map(|journal_item| match journal_item {
+            JournalItem::PlainXact(xact) => unbalanced_message(xact).map(|message| Diagnostic {
+                range: xact_header_range(content, xact),
+                severity: Some(DiagnosticSeverity::ERROR),
+                message,
+                ..Diagnostic::default()
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+/// The range of the first line of a transaction (date, payee, etc), excluding
+/// any trailing whitespace.
+fn xact_header_range(content: &str, xact: &PlainXact) -> LspRange {
+    let row = xact.range.start_point.row;
+    let line = content.lines().nth(row).unwrap_or("");
+
+    LspRange {
+        start: Position::new(row as u32, xact.range.start_point.column as u32),
+        end: Position::new(row as u32, line.trim_end().len() as u32),
+    }
+}
+
+/// Returns a message describing why the transaction does not balance, or None if
+/// it does balance (or if we can't tell).
+fn unbalanced_message(xact: &PlainXact) -> Option<String> {
+    // real postings must balance, as must balanced virtual postings ([Account]);
+    // virtual postings ((Account)) don't need to balance at all
+    let real = xact
+        .postings
+        .iter()
+        .filter(|p| !p.account.starts_with(['(', '[']))
+        .collect::<Vec<_>>();
+    let balanced_virtual = xact
+        .postings
+        .iter()
+        .filter(|p| p.account.starts_with('['))
+        .collect::<Vec<_>>();
+
+    [real, balanced_virtual].into_iter().find_map(|postings| {
+        let mut null_postings = 0;
+        let mut has_cost = false;
+        let mut residual = Balance::new();
+
+        for posting in postings {
+            match (&posting.amount, &posting.balance_assertion) {
+                (Some(_), _) => {}
+                // a balance assignment (`Account  = $100`); the amount depends on
+                // the running balance, so we can't check this group
+                (None, Some(_)) => return None,
+                (None, None) => {
+                    null_postings += 1;
+                    continue;
+                }
+            }
+
+            has_cost |= posting.price.is_some() || posting.lot_price.is_some();
+            residual.add(&posting.cost()?);
+        }
+
+        if null_postings > 1 {
+            return Some("Only one posting with null amount allowed per transaction".to_string());
+        }
+        if null_postings == 1 {
+            // the null posting balances the transaction
+            return None;
+        }
+
+        match residual.amounts().collect::<Vec<_>>().as_slice() {
+            [] => None,
+            // like ledger, two commodities w/o an explicit cost imply an
+            // exchange rate
+            [a, b] if !has_cost && a.negative != b.negative => None,
+            _ => Some(format!(
+                "Transaction does not balance; unbalanced remainder is {residual}"
+            )),
+        }
+    })
+}
+
+/// A diagnostic for every `ERROR` and `MISSING` node in the tree. Nodes
+/// within an `ERROR` node aren't reported separately.
+pub fn parse_errors(tree: &Tree, content: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    if !tree.root_node().has_error() {
+        return diagnostics;
+    }
+
+    let mut cursor = tree.walk();
+    'nodes: loop {
+        let node = cursor.node();
+        if node.is_error() || node.is_missing() {
+            diagnostics.push(Diagnostic {
+                range: first_line_range(content, node),
+                severity: Some(DiagnosticSeverity::ERROR),
+                message: parse_error_message(content, node),
+                ..Diagnostic::default()
+            });
+        } else if node.has_error() && cursor.goto_first_child() {
+            continue;
+        }
+
+        // next sibling, or the next sibling of the closest ancestor
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'nodes;
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// eg "expected amount after account" or "missing date"
+fn parse_error_message(content: &str, node: Node) -> String {
+    if node.is_missing() {
+        return if node.is_named() {
+            format!("missing {}", describe(node.kind()))
+        } else {
+            format!("missing `{}`", node.kind())
+        };
+    }
+
+    let text = content
+        .get(node.start_byte()..node.end_byte())
+        .and_then(|text| text.trim().lines().next())
+        .unwrap_or("");
+    let text = match text.char_indices().nth(20) {
+        Some((i, _)) => format!("{}…", &text[..i]),
+        None => text.to_string(),
+    };
+
+    let parent = node.parent().map(|parent| parent.kind());
+    let previous = node.prev_named_sibling().map(|previous| previous.kind());
+    match (parent, previous) {
+        (Some("posting"), Some("account")) => "expected amount after account".to_string(),
+        (Some("posting"), Some("amount")) => {
+            "expected price, balance assertion or note after amount".to_string()
+        }
+        (Some(parent @ ("price" | "lot_price" | "balance_assertion")), _) => {
+            format!("expected amount in {}", describe(parent))
+        }
+        (Some("plain_xact"), Some(previous @ ("date" | "effective_date" | "status" | "code"))) => {
+            format!("expected payee after {}", describe(previous))
+        }
+        (None | Some("source_file" | "journal_item"), _) if text.is_empty() => {
+            "syntax error".to_string()
+        }
+        (None | Some("source_file" | "journal_item"), _) => format!("unexpected `{text}`"),
+        (Some(parent), Some(previous)) => format!(
+            "unexpected `{text}` after {} in {}",
+            describe(previous),
+            describe(parent)
+        ),
+        (Some(parent), None) => format!("unexpected `{text}` in {}", describe(parent)),
+    }
+}
+
+/// A node kind as it would be described to a user
+fn describe(kind: &str) -> String {
+    match kind {
+        "plain_xact" | "xact" => "transaction".to_string(),
+        "periodic_xact" => "periodic transaction".to_string(),
+        "automated_xact" => "automated transaction".to_string(),
+        "negative_quantity" => "quantity".to_string(),
+        kind => kind.replace('_', " "),
+    }
+}
+
+/// The range of the node, up to the end of its first line, excluding any
+/// trailing whitespace.
+fn first_line_range(content: &str, node: Node) -> LspRange {
+    let start = node.start_position();
+    let end = if node.end_position().row == start.row {
+        node.end_position().column
+    } else {
+        let line = content.lines().nth(start.row).unwrap_or("");
+        line.trim_end().len().max(start.column)
+    };
+
+    LspRange {
+        start: Position::new(start.row as u32, start.column as u32),
+        end: Position::new(start.row as u32, end as u32),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unbalanced_transactions() -> Result<()> {
+        let source = textwrap::dedent(
+            "
+            2024/01/01 Balanced
+                Expenses:Food    $10.00
+                Assets:Checking  $-10.00
+
+            2024/01/02 Elided
+                Expenses:Food    $10.00
+                Assets:Checking
+
+            2024/01/03 Unbalanced
+                Expenses:Food    $10.00
+                Assets:Checking  $-9.50
+
+            2024/01/04 Too Many Nulls
+                Expenses:Food
+                Assets:Checking
+
+            2024/01/05 Virtual
+                Expenses:Food    $10.00
+                Assets:Checking  $-10.00
+                (Budget:Food)    $-10.00
+
+            2024/01/06 Priced
+                Assets:Brokerage    10 AAPL @ $5.00
+                Assets:Checking     $-50.00
+            ",
+        );
+        let mut parser = tree_sitter::Parser::new();
+        let tree = parser
+            .set_language(&tree_sitter::Language::new(tree_sitter_ledger::LANGUAGE))
+            .ok()
+            .and_then(|_| parser.parse(&source, None));
+        let tree = match tree {
+            Some(tree) => tree,
+            None => panic!("parsing source"),
+        };
+
+        let diagnostics = unbalanced_transactions(&tree, &source)?
+            .into_iter()
+            .map(|d| (d.range.start.line, d.range.end.character, d.message))
+            .collect::<Vec<_>>();
+
+        insta::assert_debug_snapshot!(diagnostics,
+        @r#"
+        [
+            (
+                9,
+                21,
+                "Transaction does not balance; unbalanced remainder is $0.50",
+            ),
+            (
+                13,
+                25,
+                "Only one posting with null amount allowed per transaction",
+            ),
+        ]
+        "#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let source = textwrap::dedent(
+            "
+            2024/01/01 Valid
+                Expenses:Food    $10.00
+                Assets:Checking
+
+            2024/01/02 Missing Amount
+                Expenses:Food    &&&
+                Assets:Checking
+
+            2024/01/03 Missing Price
+                Assets:Cash    10 EUR @
+                Assets:Checking
+
+            &&& ???
+            ",
+        );
+        let mut parser = tree_sitter::Parser::new();
+        let tree = parser
+            .set_language(&tree_sitter::Language::new(tree_sitter_ledger::LANGUAGE))
+            .ok()
+            .and_then(|_| parser.parse(&source, None));
+        let tree = match tree {
+            Some(tree) => tree,
+            None => panic!("parsing source"),
+        };
+
+        let mut errors = parse_errors(&tree, &source)
+            .into_iter()
+            .map(|d| (d.range.start.line, d.message))
+            .collect::<Vec<_>>();
+        errors.dedup();
+        insta::assert_debug_snapshot!(errors, @r#"
+        [
+            (
+                5,
+                "expected amount after account",
+            ),
+            (
+                9,
+                "missing quantity",
+            ),
+            (
+                12,
+                "unexpected `&&& ???`",
+            ),
+        ]
+        "#);
+    }
+}
@@ -15,101 +15,15 @@ use std::cmp::Ordering;
 use std::fmt::Display;
 use std::io::Write;
 
+use crate::backend_amount::{Balance, Decimal, QuantityStyle};
+
 mod ledger {
     #![allow(clippy::all, clippy::expect_used, clippy::unwrap_used)]
     include!("./type_sitter/ledger.rs");
 }
 
 pub fn format(content: &str, sort_transactions: bool) -> Result<String> {
-    //
-    // parse with tree sitter
-    //
-    let mut parser = Parser::<ledger::SourceFile>::new(&tree_sitter_ledger::LANGUAGE.into())
-        .map_err(|_| anyhow!("loading Ledger tree-sitter grammar"))?;
-    let tree = parser
-        .parse(content, None)
-        .map_err(|_| anyhow!("parsing content"))?;
-    let root_node = tree.root_node().map_err(|err| anyhow!("{err}"))?;
-    let mut raw_cursor = root_node.raw().walk();
-
-    //
-    // convert from tree sitter to internal types (easier to use)
-    //
-    let journal_items: Vec<JournalItem> = root_node
-        .raw()
-        .children(&mut raw_cursor)
-        .map(|journal_item| {
-            match TS_JournalItem::try_from_raw(journal_item) {
-                Ok(journal_item) => {
-                    let journal_item = match journal_item.child() {
-                        Ok(journal_item) => journal_item,
-                        Err(_) => {
-                            return JournalItem::Error(substring(content, journal_item.range()))
-                        }
-                    };
-
-                    if journal_item.has_error() {
-                        // dbg!(substring(content, journal_item.range()));
-                        // dbg!(journal_item.to_sexp());
-                        return JournalItem::Error(substring(content, journal_item.range()));
-                    }
-
-                    match journal_item {
-                        JournalItems::Comment(comment) => JournalItem::Comment(Comment {
-                            range: comment.range(),
-                            content: substring(content, comment.range()),
-                        }),
-                        JournalItems::Directive(directive) => {
-                            match Directive::from_ts_xact(directive, content, || tree.walk()) {
-                                Ok(directive) => JournalItem::Directive(directive),
-                                Err(_) => JournalItem::Error(substring(content, directive.range())),
-                            }
-                        }
-                        JournalItems::Xact(xact) => match xact.child() {
-                            Ok(Transactions::AutomatedXact(xact)) => {
-                                match AutomatedXact::from_ts_xact(xact, content, || tree.walk()) {
-                                    Ok(xact) => JournalItem::AutomatedXact(xact),
-                                    Err(_) => JournalItem::Error(substring(content, xact.range())),
-                                }
-                            }
-                            Ok(Transactions::PeriodicXact(xact)) => {
-                                match PeriodicXact::from_ts_xact(xact, content, || tree.walk()) {
-                                    Ok(xact) => JournalItem::PeriodicXact(xact),
-                                    Err(_) => JournalItem::Error(substring(content, xact.range())),
-                                }
-                            }
-                            Ok(Transactions::PlainXact(xact)) => {
-                                match PlainXact::from_ts_xact(xact, content, || tree.walk()) {
-                                    Ok(xact) => JournalItem::PlainXact(xact),
-                                    Err(_) => JournalItem::Error(substring(content, xact.range())),
-                                }
-                            }
-                            Err(_) => JournalItem::Error(substring(content, xact.range())),
-                        },
-                        JournalItems::BlockComment(comment) => {
-                            // TODO
-                            JournalItem::Comment(Comment {
-                                range: comment.range(),
-                                content: substring(content, comment.range()),
-                            })
-                        }
-                        JournalItems::Test(test) => {
-                            // TODO
-                            JournalItem::Other(substring(content, test.range()))
-                        }
-                    }
-                }
-                Err(err) => match err.cause() {
-                    type_sitter::IncorrectKindCause::Error => {
-                        // dbg!(substring(content, err.node.range()));
-                        JournalItem::Error(substring(content, err.node.range()))
-                    }
-                    type_sitter::IncorrectKindCause::Missing
-                    | type_sitter::IncorrectKindCause::OtherKind(_) => JournalItem::Skip,
-                },
-            }
-        })
-        .collect();
+    let journal_items = parse(content)?;
 
     let journal_items = if !sort_transactions {
         journal_items
@@ -231,12 +145,114 @@ pub fn format(content: &str, sort_transactions: bool) -> Result<String> {
     Ok(String::from_utf8(buf)?)
 }
 
+/// Parse a document into a list of journal items. Items that could not be
+/// parsed are returned as `JournalItem::Error`, containing the original source.
+pub fn parse(content: &str) -> Result<Vec<JournalItem>> {
+    //
+    // parse with tree sitter
+    //
+    let mut parser = Parser::<ledger::SourceFile>::new(&tree_sitter_ledger::LANGUAGE.into())
+        .map_err(|_| anyhow!("loading Ledger tree-sitter grammar"))?;
+    let tree = parser
+        .parse(content, None)
+        .map_err(|_| anyhow!("parsing content"))?;
+    parse_tree(tree.raw(), content)
+}
+
+/// Like `parse`, but from the document's (cached) Tree
+pub fn parse_tree(tree: &tree_sitter::Tree, content: &str) -> Result<Vec<JournalItem>> {
+    let tree = type_sitter::Tree::<ledger::SourceFile>::wrap(tree.clone());
+    let root_node = tree.root_node().map_err(|err| anyhow!("{err}"))?;
+    let mut raw_cursor = root_node.raw().walk();
+
+    //
+    // convert from tree sitter to internal types (easier to use)
+    //
+    let journal_items: Vec<JournalItem> = root_node
+        .raw()
+        .children(&mut raw_cursor)
+        .map(|journal_item| {
+            match TS_JournalItem::try_from_raw(journal_item) {
+                Ok(journal_item) => {
+                    let journal_item = match journal_item.child() {
+                        Ok(journal_item) => journal_item,
+                        Err(_) => {
+                            return JournalItem::Error(substring(content, journal_item.range()))
+                        }
+                    };
+
+                    if journal_item.has_error() {
+                        // dbg!(substring(content, journal_item.range()));
+                        // dbg!(journal_item.to_sexp());
+                        return JournalItem::Error(substring(content, journal_item.range()));
+                    }
+
+                    match journal_item {
+                        JournalItems::Comment(comment) => JournalItem::Comment(Comment {
+                            range: comment.range(),
+                            content: substring(content, comment.range()),
+                        }),
+                        JournalItems::Directive(directive) => {
+                            match Directive::from_ts_xact(directive, content, || tree.walk()) {
+                                Ok(directive) => JournalItem::Directive(directive),
+                                Err(_) => JournalItem::Error(substring(content, directive.range())),
+                            }
+                        }
+                        JournalItems::Xact(xact) => match xact.child() {
+                            Ok(Transactions::AutomatedXact(xact)) => {
+                                match AutomatedXact::from_ts_xact(xact, content, || tree.walk()) {
+                                    Ok(xact) => JournalItem::AutomatedXact(xact),
+                                    Err(_) => JournalItem::Error(substring(content, xact.range())),
+                                }
+                            }
+                            Ok(Transactions::PeriodicXact(xact)) => {
+                                match PeriodicXact::from_ts_xact(xact, content, || tree.walk()) {
+                                    Ok(xact) => JournalItem::PeriodicXact(xact),
+                                    Err(_) => JournalItem::Error(substring(content, xact.range())),
+                                }
+                            }
+                            Ok(Transactions::PlainXact(xact)) => {
+                                match PlainXact::from_ts_xact(xact, content, || tree.walk()) {
+                                    Ok(xact) => JournalItem::PlainXact(xact),
+                                    Err(_) => JournalItem::Error(substring(content, xact.range())),
+                                }
+                            }
+                            Err(_) => JournalItem::Error(substring(content, xact.range())),
+                        },
+                        JournalItems::BlockComment(comment) => {
+                            // TODO
+                            JournalItem::Comment(Comment {
+                                range: comment.range(),
+                                content: substring(content, comment.range()),
+                            })
+                        }
+                        JournalItems::Test(test) => {
+                            // TODO
+                            JournalItem::Other(substring(content, test.range()))
+                        }
+                    }
+                }
+                Err(err) => match err.cause() {
+                    type_sitter::IncorrectKindCause::Error => {
+                        // dbg!(substring(content, err.node.range()));
+                        JournalItem::Error(substring(content, err.node.range()))
+                    }
+                    type_sitter::IncorrectKindCause::Missing
+                    | type_sitter::IncorrectKindCause::OtherKind(_) => JournalItem::Skip,
+                },
+            }
+        })
+        .collect();
+
+    Ok(journal_items)
+}
+
 fn substring(content: &str, range: Range) -> String {
     content[range.start_byte..range.end_byte].trim().to_string()
 }
 
 #[derive(Clone, Eq, PartialEq)]
-enum JournalItem {
+pub enum JournalItem {
     PlainXact(PlainXact),
     PeriodicXact(PeriodicXact),
     AutomatedXact(AutomatedXact),
@@ -247,14 +263,28 @@ enum JournalItem {
     Skip,
 }
 
+impl JournalItem {
+    /// The range of the item in the source, if it was parsed
+    pub fn range(&self) -> Option<Range> {
+        match self {
+            JournalItem::PlainXact(xact) => Some(xact.range),
+            JournalItem::PeriodicXact(xact) => Some(xact.range),
+            JournalItem::AutomatedXact(xact) => Some(xact.range),
+            JournalItem::Comment(comment) => Some(comment.range),
+            JournalItem::Directive(directive) => Some(directive.range),
+            JournalItem::Other(_) | JournalItem::Error(_) | JournalItem::Skip => None,
+        }
+    }
+}
+
 #[derive(Clone, Eq, PartialEq)]
-struct Comment {
+pub struct Comment {
     range: Range,
     content: String,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
-struct Directive {
+pub struct Directive {
     range: Range,
     name: String,
     content: String,
@@ -263,22 +293,22 @@ struct Directive {
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
-struct PlainXact {
-    range: Range,
+pub struct PlainXact {
+    pub range: Range,
 
-    date: Option<String>,
+    pub date: Option<String>,
     effective_date: Option<String>,
     status: Option<String>,
     code: Option<String>,
     payee: Option<String>,
-    postings: Vec<Posting>,
+    pub postings: Vec<Posting>,
 
     payee_note: Option<String>,
     notes: Vec<String>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
-struct PeriodicXact {
+pub struct PeriodicXact {
     range: Range,
 
     interval: String,
@@ -289,7 +319,7 @@ struct PeriodicXact {
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
-struct AutomatedXact {
+pub struct AutomatedXact {
     range: Range,
 
     query: String,
@@ -300,39 +330,45 @@ struct AutomatedXact {
 }
 
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
-enum CommodityPosition {
+pub enum CommodityPosition {
     #[default]
     Left,
     Right,
 }
 
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
-struct Posting {
-    account: String,
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Posting {
+    pub range: Range,
+
+    pub account: String,
 
     status: Option<String>,
 
-    amount: Option<Amount>,
-    lot_price: Option<Amount>,
-    price: Option<Price>,
-    balance_assertion: Option<Amount>,
+    pub amount: Option<Amount>,
+    pub lot_price: Option<Amount>,
+    pub price: Option<Price>,
+    pub balance_assertion: Option<Amount>,
 
     inline_note: Option<String>,
     trailing_notes: Vec<String>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
-enum Price {
+pub enum Price {
     Unit(Amount),
     Total(Amount),
 }
 
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
-struct Amount {
-    negative: bool,
-    commodity_position: CommodityPosition,
-    commodity: Option<String>,
-    quantity: Option<String>,
+pub struct Amount {
+    pub negative: bool,
+    pub commodity_position: CommodityPosition,
+    pub commodity: Option<String>,
+    pub quantity: Option<String>,
+
+    /// The signed, exact value of `quantity`, if it could be parsed
+    pub value: Option<Decimal>,
+    pub style: QuantityStyle,
 }
 
 impl<'tree> Directive {
@@ -549,6 +585,43 @@ impl Display for PlainXact {
     }
 }
 
+impl PlainXact {
+    /// The amount of each posting, including the amount that ledger would infer
+    /// for the posting (if any) that was left without one. Postings whose amount
+    /// can't be determined (eg balance assignments) have an empty balance.
+    pub fn posting_balances(&self) -> Vec<Balance> {
+        // real postings and balanced virtual postings ([Account]) are balanced
+        // separately, so each may have a posting w/ an inferred amount
+        let group = |posting: &Posting| match posting.account.chars().next() {
+            Some('(') => 0,
+            Some('[') => 1,
+            _ => 2,
+        };
+
+        let mut residuals = [Balance::new(), Balance::new(), Balance::new()];
+        for posting in self.postings.iter() {
+            if let Some(cost) = posting.cost() {
+                residuals[group(posting)].add(&cost);
+            }
+        }
+
+        self.postings
+            .iter()
+            .map(|posting| {
+                let mut balance = Balance::new();
+                match (&posting.amount, &posting.balance_assertion) {
+                    (Some(amount), _) => balance.add(amount),
+                    (None, None) if group(posting) != 0 => {
+                        balance = residuals[group(posting)].negated();
+                    }
+                    (None, _) => {}
+                }
+                balance
+            })
+            .collect()
+    }
+}
+
 impl<'tree> PeriodicXact {
     fn new(range: Range) -> Self {
         Self {
@@ -672,12 +745,26 @@ impl Display for AutomatedXact {
 }
 
 impl<'tree> Posting {
+    fn new(range: Range) -> Self {
+        Self {
+            range,
+            account: String::new(),
+            status: None,
+            amount: None,
+            lot_price: None,
+            price: None,
+            balance_assertion: None,
+            inline_note: None,
+            trailing_notes: Vec::new(),
+        }
+    }
+
     fn from_ts_posting<'a, T: Fn() -> TreeCursor<'tree>>(
         posting: ledger::Posting<'tree>,
         content: &str,
         cursor_fn: T,
     ) -> Result<Self> {
-        let mut p = Posting::default();
+        let mut p = Posting::new(posting.range());
 
         let mut cursor = cursor_fn();
         for p_child in posting.children(&mut cursor) {
@@ -728,6 +815,38 @@ impl<'tree> Posting {
     }
 }
 
+/// The name of an account, without the parens or brackets of a virtual posting
+pub fn account_name(account: &str) -> &str {
+    account
+        .trim_start_matches(['(', '['])
+        .trim_end_matches([')', ']'])
+}
+
+impl Posting {
+    /// The account name, without the parens or brackets of virtual postings
+    pub fn account_name(&self) -> &str {
+        account_name(&self.account)
+    }
+
+    /// The amount of this posting for the purposes of balancing its transaction;
+    /// ie converted to the commodity of its price or lot price, if any.
+    pub fn cost(&self) -> Option<Amount> {
+        let amount = self.amount.as_ref()?;
+        let value = amount.value.as_ref()?;
+
+        match (&self.price, &self.lot_price) {
+            (Some(Price::Unit(price)), _) | (None, Some(price)) => {
+                Some(price.with_value(value * &price.value.as_ref()?.abs()))
+            }
+            (Some(Price::Total(price)), _) => {
+                let total = price.value.as_ref()?.abs();
+                Some(price.with_value(if value.is_negative() { -total } else { total }))
+            }
+            (None, None) => Some(amount.clone()),
+        }
+    }
+}
+
 impl Display for Posting {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut amount = match &self.amount {
@@ -815,10 +934,32 @@ impl<'tree> Amount {
             }
         }
 
+        if let Some((value, style)) = a.quantity.as_deref().and_then(Decimal::parse) {
+            a.value = Some(if a.negative && !value.is_negative() {
+                -value
+            } else {
+                value
+            });
+            a.style = style;
+        }
+
         Ok(a)
     }
 }
 
+impl Amount {
+    /// A copy of this amount (ie w/ the same commodity and style), but with a
+    /// different value.
+    pub fn with_value(&self, value: Decimal) -> Self {
+        Self {
+            negative: value.is_negative(),
+            quantity: Some(value.abs().to_string_with_style(&self.style)),
+            value: Some(value),
+            ..self.clone()
+        }
+    }
+}
+
 impl Display for Amount {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let negative = if self.negative { "-" } else { "" };
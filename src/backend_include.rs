@@ -0,0 +1,262 @@
+use std::path::{Component, Path, PathBuf};
+
+use regex::Regex;
+use walkdir::WalkDir;
+
+/// True if the filename of an `include` directive is a glob pattern. A `[`
+/// is only a wildcard when it's closed by a `]`.
+pub fn is_glob(filename: &str) -> bool {
+    filename.contains(['*', '?'])
+        || filename
+            .split_once('[')
+            .is_some_and(|(_, rest)| rest.contains(']'))
+}
+
+/// The files included by an `include` directive, relative to `dir` (the
+/// directory of the including file).
+///
+/// A literal filename resolves to a single path, whether or not it exists. A
+/// directory includes every file within it, and a glob pattern every file it
+/// matches. Either way the paths are sorted, like ledger.
+pub fn include_paths(dir: &Path, filename: &str) -> Vec<PathBuf> {
+    let path = Path::new(filename);
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        dir.join(path)
+    };
+
+    if is_glob(filename) {
+        glob_paths(&path)
+    } else if path.is_dir() {
+        let mut paths = WalkDir::new(&path)
+            .min_depth(1)
+            .max_depth(1)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file() && !is_hidden(entry.file_name()))
+            .map(|entry| entry.into_path())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    } else {
+        vec![path]
+    }
+}
+
+/// Files matching the glob pattern. Everything up to the first component
+/// containing a wildcard is a literal directory to search.
+fn glob_paths(pattern: &Path) -> Vec<PathBuf> {
+    let mut base = PathBuf::new();
+    let mut rest = Vec::new();
+    for component in pattern.components() {
+        match component {
+            Component::Normal(name) if rest.is_empty() && !is_glob(&name.to_string_lossy()) => {
+                base.push(name)
+            }
+            Component::Normal(name) => rest.push(name.to_string_lossy().to_string()),
+            component if rest.is_empty() => base.push(component),
+            // `..` etc after a wildcard isn't supported
+            _ => return Vec::new(),
+        }
+    }
+
+    let re = match Regex::new(&glob_regex(&rest.join("/"))) {
+        Ok(re) => re,
+        Err(_) => return Vec::new(),
+    };
+    let max_depth = if rest.iter().any(|c| c.contains("**")) {
+        usize::MAX
+    } else {
+        rest.len()
+    };
+
+    let mut paths = WalkDir::new(&base)
+        .min_depth(1)
+        .max_depth(max_depth)
+        .into_iter()
+        .filter_entry(|entry| !is_hidden(entry.file_name()))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| {
+            let relative = match entry.path().strip_prefix(&base) {
+                Ok(relative) => relative,
+                Err(_) => return false,
+            };
+            let relative = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            re.is_match(&relative)
+        })
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+/// A regex matching the same `/` separated relative paths as the glob. `*`
+/// and `?` don't match `/`, `**/` matches any number of directories and
+/// `[...]` (or `[!...]`) matches a single character.
+fn glob_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' if chars.clone().any(|c| c == ']') => {
+                let class = chars.by_ref().take_while(|c| *c != ']').collect::<String>();
+                let class = match class.strip_prefix('!') {
+                    Some(class) => format!("^{class}"),
+                    None => class,
+                };
+                re.push('[');
+                re.push_str(&class.replace('\\', "\\\\").replace('[', "\\["));
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Editor swap files, `.git` etc
+fn is_hidden(name: &std::ffi::OsStr) -> bool {
+    name.to_string_lossy().starts_with('.')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_glob_regex() {
+        insta::assert_debug_snapshot!(
+            [
+                "*.ledger",
+                "2024/*.dat",
+                "**/*.ledger",
+                "20[!0]?/a.ledger",
+                "[draft*.ledger",
+            ]
+            .map(glob_regex),
+            @r#"
+        [
+            "^[^/]*\\.ledger$",
+            "^2024/[^/]*\\.dat$",
+            "^(?:.*/)?[^/]*\\.ledger$",
+            "^20[^0][^/]/a\\.ledger$",
+            "^\\[draft[^/]*\\.ledger$",
+        ]
+        "#
+        );
+    }
+
+    #[test]
+    fn test_is_glob() {
+        insta::assert_debug_snapshot!(
+            ["main.ledger", "*.ledger", "20??.ledger", "20[0-9].ledger", "[draft].ledger", "[draft.ledger"]
+                .map(is_glob),
+            @r"
+        [
+            false,
+            true,
+            true,
+            true,
+            true,
+            false,
+        ]
+        "
+        );
+    }
+
+    #[test]
+    fn test_include_paths() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join("ledger-lsp-test-include-paths");
+        let _ = fs::remove_dir_all(&dir);
+        for file in [
+            "main.ledger",
+            "accounts.dat",
+            "2024/b.ledger",
+            "2024/a.ledger",
+            "2024/notes.txt",
+            "2024/.a.ledger.swp",
+            "2024/q1/c.ledger",
+        ] {
+            let path = dir.join(file);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, "")?;
+        }
+
+        let relative = |filename: &str| {
+            include_paths(&dir, filename)
+                .iter()
+                .map(|path| {
+                    path.strip_prefix(&dir)
+                        .unwrap_or(path)
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        insta::assert_debug_snapshot!(
+            [
+                relative("accounts.dat"),
+                relative("missing.dat"),
+                relative("*.ledger"),
+                relative("2024/*.ledger"),
+                relative("2024"),
+                relative("**/*.ledger"),
+                relative("2023/*.ledger"),
+            ],
+            @r#"
+        [
+            [
+                "accounts.dat",
+            ],
+            [
+                "missing.dat",
+            ],
+            [
+                "main.ledger",
+            ],
+            [
+                "2024/a.ledger",
+                "2024/b.ledger",
+            ],
+            [
+                "2024/a.ledger",
+                "2024/b.ledger",
+                "2024/notes.txt",
+            ],
+            [
+                "2024/a.ledger",
+                "2024/b.ledger",
+                "2024/q1/c.ledger",
+                "main.ledger",
+            ],
+            [],
+        ]
+        "#
+        );
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use tree_sitter::{Node, Range, Tree};
+
+use crate::backend_format::account_name;
+use crate::backend_include;
+
+/// An `include` directive, and the files it includes
+#[derive(Clone, Debug)]
+pub struct Include {
+    /// The range of the directive's filename
+    pub range: Range,
+    /// The filename as written, without quotes; may be a directory or glob
+    pub filename: String,
+    /// Each file that's included, in order, see `backend_include::include_paths`
+    pub paths: Vec<String>,
+}
+
+/// Names used or declared in a document
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Symbols {
+    /// Accounts as written, eg `(Budget:Food)`
+    pub accounts: HashSet<String>,
+    /// Names of the accounts declared by `account` directives
+    pub declared_accounts: HashSet<String>,
+    pub payees: HashSet<String>,
+    /// Tags, in `; Tag: value` notes
+    pub tags: HashSet<String>,
+    /// Commodities of amounts
+    pub commodities: HashSet<String>,
+    /// Commodities declared by `commodity` directives
+    pub declared_commodities: HashSet<String>,
+}
+
+impl Symbols {
+    pub fn extend(&mut self, other: &Symbols) {
+        self.accounts.extend(other.accounts.iter().cloned());
+        self.declared_accounts
+            .extend(other.declared_accounts.iter().cloned());
+        self.payees.extend(other.payees.iter().cloned());
+        self.tags.extend(other.tags.iter().cloned());
+        self.commodities.extend(other.commodities.iter().cloned());
+        self.declared_commodities
+            .extend(other.declared_commodities.iter().cloned());
+    }
+}
+
+/// A parsed document, and what's been extracted from it
+pub struct IndexedFile {
+    pub content: String,
+    pub tree: Tree,
+    pub includes: Vec<Include>,
+    pub symbols: Symbols,
+
+    // LSP version, for documents open in the client
+    pub version: Option<i32>,
+    // documents open in the client are never evicted
+    pub open: bool,
+    // `LedgerBackend::cache_clock` when the document was last parsed or used
+    pub last_used: u64,
+}
+
+impl IndexedFile {
+    /// Extract the includes and symbols of a freshly parsed document. The
+    /// paths of an include are reused from the `previous` includes of the
+    /// document with the same filename, so the filesystem is only searched
+    /// again when the directives change, see `resolve_includes`.
+    pub fn new(path: &str, content: String, tree: Tree, previous: &[Include]) -> Self {
+        let mut includes = Vec::new();
+        let mut symbols = Symbols::default();
+        let dir = Path::new(path).parent().unwrap_or(Path::new("/"));
+
+        let mut cursor = tree.walk();
+        'nodes: loop {
+            let node = cursor.node();
+            let text = node_text(&content, node);
+            let parent = node.parent().map(|parent| parent.kind());
+            match node.kind() {
+                "filename" => {
+                    let filename = text.trim_matches(['"', '\'']).to_string();
+                    let paths = match previous.iter().find(|include| include.filename == filename) {
+                        Some(include) => include.paths.clone(),
+                        None => include_paths(dir, &filename),
+                    };
+                    includes.push(Include {
+                        range: node.range(),
+                        filename,
+                        paths,
+                    });
+                }
+                "account" => {
+                    if parent == Some("account_directive") {
+                        symbols
+                            .declared_accounts
+                            .insert(account_name(text).to_string());
+                    }
+                    symbols.accounts.insert(text.to_string());
+                }
+                "payee" => {
+                    symbols.payees.insert(text.to_string());
+                }
+                "note" => {
+                    if let Some(tag) = note_tag(text) {
+                        symbols.tags.insert(tag.to_string());
+                    }
+                }
+                "commodity" if parent == Some("commodity_directive") => {
+                    symbols.declared_commodities.insert(text.to_string());
+                }
+                "commodity" if parent == Some("amount") && !text.is_empty() => {
+                    symbols.commodities.insert(text.to_string());
+                }
+                _ => {}
+            }
+
+            if cursor.goto_first_child() {
+                continue;
+            }
+            // next sibling, or the next sibling of the closest ancestor
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() {
+                    break 'nodes;
+                }
+            }
+        }
+
+        drop(cursor);
+
+        Self {
+            content,
+            tree,
+            includes,
+            symbols,
+            version: None,
+            open: false,
+            last_used: 0,
+        }
+    }
+
+    /// Resolve the included paths again, eg after files were created
+    fn resolve_includes(&mut self, path: &str) {
+        let dir = Path::new(path).parent().unwrap_or(Path::new("/"));
+        for include in self.includes.iter_mut() {
+            include.paths = include_paths(dir, &include.filename);
+        }
+    }
+
+    /// Each included path, with the range of the filename that includes it
+    pub fn included_paths(&self) -> impl Iterator<Item = (Range, &str)> {
+        self.includes.iter().flat_map(|include| {
+            include
+                .paths
+                .iter()
+                .map(|path| (include.range, path.as_str()))
+        })
+    }
+}
+
+/// The included paths, as strings
+fn include_paths(dir: &Path, filename: &str) -> Vec<String> {
+    backend_include::include_paths(dir, filename)
+        .into_iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect()
+}
+
+/// The trimmed text of a node
+fn node_text<'a>(content: &'a str, node: Node) -> &'a str {
+    content
+        .get(node.start_byte()..node.end_byte().min(content.len()))
+        .unwrap_or("")
+        .trim()
+}
+
+/// The tag of a `; Tag: value` note
+pub fn note_tag(note: &str) -> Option<&str> {
+    match note
+        // https://ledger-cli.org/doc/ledger3.html#Commenting-on-your-Journal
+        .trim_start_matches([' ', '\t', ';', '#', '%', '|', '*'])
+        .split_once(": ")
+    {
+        Some((tag, _)) if !tag.contains(' ') => Some(tag),
+        Some(_) | None => None,
+    }
+}
+
+/// A document in the include graph of a root, see `WorkspaceIndex::documents`
+pub struct Document<'a> {
+    pub path: &'a str,
+    pub file: &'a IndexedFile,
+    /// The documents that include it, from the root, each with the range of
+    /// the `include` directive of the next (or of this document)
+    pub includers: Vec<(&'a str, Range)>,
+}
+
+/// The parsed documents of the workspace by path, which form the include
+/// graph. Documents are (re)indexed as they're opened, changed or included.
+#[derive(Default)]
+pub struct WorkspaceIndex {
+    files: HashMap<String, IndexedFile>,
+    /// Incremented whenever the include graph may have changed, or the
+    /// symbols of a document that isn't open
+    generation: u64,
+}
+
+impl WorkspaceIndex {
+    pub fn get(&self, path: &str) -> Option<&IndexedFile> {
+        self.files.get(path)
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut IndexedFile> {
+        self.files.get_mut(path)
+    }
+
+    pub fn insert(&mut self, path: &str, file: IndexedFile) {
+        let changed = match self.files.get(path) {
+            Some(previous) => {
+                let included = previous.included_paths().map(|(_, path)| path);
+                included.ne(file.included_paths().map(|(_, path)| path))
+                    || (!file.open && previous.symbols != file.symbols)
+            }
+            None => true,
+        };
+        self.files.insert(path.to_string(), file);
+        if changed {
+            self.graph_changed();
+        }
+    }
+
+    pub fn remove(&mut self, path: &str) -> Option<IndexedFile> {
+        let removed = self.files.remove(path);
+        if removed.is_some() {
+            self.graph_changed();
+        }
+        removed
+    }
+
+    pub fn retain(&mut self, f: impl FnMut(&String, &mut IndexedFile) -> bool) {
+        self.files.retain(f)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &IndexedFile)> {
+        self.files.iter()
+    }
+
+    /// Resolve the includes of every document again
+    pub fn resolve_includes(&mut self) {
+        for (path, file) in self.files.iter_mut() {
+            file.resolve_includes(path);
+        }
+        self.graph_changed();
+    }
+
+    /// The include graph may have changed other than by (re)indexing a
+    /// document, eg the roots are different
+    pub fn graph_changed(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Changes when the include graph may have changed, see `graph_changed`,
+    /// or the symbols of a document that isn't open
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The root and every indexed document it includes (recursively), depth
+    /// first, ie in the order ledger reads them. A document is only visited
+    /// the first time it's included, and documents that aren't indexed (eg
+    /// don't exist) are skipped.
+    pub fn documents<'a>(&'a self, root: &'a str) -> impl Iterator<Item = Document<'a>> {
+        let mut visited = HashSet::new();
+        let mut stack = vec![(root, Vec::new())];
+        std::iter::from_fn(move || loop {
+            let (path, includers) = stack.pop()?;
+            if !visited.insert(path) {
+                continue;
+            }
+            let file = match self.files.get(path) {
+                Some(file) => file,
+                None => continue,
+            };
+
+            // reversed, so the first include is visited next
+            let included = file.included_paths().collect::<Vec<_>>();
+            for (range, included) in included.into_iter().rev() {
+                if !visited.contains(included) {
+                    let mut includers = includers.clone();
+                    includers.push((path, range));
+                    stack.push((included, includers));
+                }
+            }
+
+            return Some(Document {
+                path,
+                file,
+                includers,
+            });
+        })
+    }
+
+    /// The `include` directive on the given row
+    pub fn include_at(&self, path: &str, row: usize) -> Option<&Include> {
+        self.files
+            .get(path)?
+            .includes
+            .iter()
+            .find(|include| include.range.start_point.row == row)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_indexed_file() {
+        let source = textwrap::dedent(
+            "
+            include accounts.ledger
+            account Assets:Checking
+            commodity $
+
+            2024/01/01 Grocer
+                ; Receipt: 123
+                Expenses:Food    10 EUR
+                Assets:Checking
+            ",
+        );
+        let mut parser = tree_sitter::Parser::new();
+        let tree = parser
+            .set_language(&tree_sitter::Language::new(tree_sitter_ledger::LANGUAGE))
+            .ok()
+            .and_then(|_| parser.parse(&source, None));
+        let tree = match tree {
+            Some(tree) => tree,
+            None => panic!("parsing source"),
+        };
+
+        let indexed = IndexedFile::new("/tmp/main.ledger", source.clone(), tree, &[]);
+        let includes = indexed
+            .includes
+            .iter()
+            .map(|include| {
+                (
+                    include.range.start_point,
+                    include.filename.as_str(),
+                    include.paths.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let sorted = |names: &HashSet<String>| {
+            let mut names = names.iter().cloned().collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let symbols = &indexed.symbols;
+
+        insta::assert_debug_snapshot!((
+            includes,
+            sorted(&symbols.accounts),
+            sorted(&symbols.declared_accounts),
+            sorted(&symbols.payees),
+            sorted(&symbols.tags),
+            sorted(&symbols.commodities),
+            sorted(&symbols.declared_commodities),
+        ),
+        @r#"
+        (
+            [
+                (
+                    Point {
+                        row: 1,
+                        column: 8,
+                    },
+                    "accounts.ledger",
+                    [
+                        "/tmp/accounts.ledger",
+                    ],
+                ),
+            ],
+            [
+                "Assets:Checking",
+                "Expenses:Food",
+            ],
+            [
+                "Assets:Checking",
+            ],
+            [
+                "Grocer",
+            ],
+            [
+                "Receipt",
+            ],
+            [
+                "EUR",
+            ],
+            [
+                "$",
+            ],
+        )
+        "#
+        );
+    }
+
+    #[test]
+    fn test_indexed_file_reuses_include_paths() {
+        let parse = |source: &str| {
+            let mut parser = tree_sitter::Parser::new();
+            match parser
+                .set_language(&tree_sitter::Language::new(tree_sitter_ledger::LANGUAGE))
+                .ok()
+                .and_then(|_| parser.parse(source, None))
+            {
+                Some(tree) => tree,
+                None => panic!("parsing source"),
+            }
+        };
+
+        let source = "include *.ledger\n";
+        let mut previous =
+            IndexedFile::new("/tmp/main.ledger", source.to_string(), parse(source), &[]);
+        for include in previous.includes.iter_mut() {
+            include.paths = vec!["/tmp/cached.ledger".to_string()];
+        }
+
+        // the glob isn't searched again, only the new directive is resolved
+        let source = "2024/01/01 Grocer\n    Expenses:Food  $10\n    Assets\n\ninclude *.ledger\ninclude other.ledger\n";
+        let indexed = IndexedFile::new(
+            "/tmp/main.ledger",
+            source.to_string(),
+            parse(source),
+            &previous.includes,
+        );
+        let includes = indexed
+            .includes
+            .iter()
+            .map(|include| (include.range.start_point.row, include.paths.clone()))
+            .collect::<Vec<_>>();
+        insta::assert_debug_snapshot!(includes,
+        @r#"
+        [
+            (
+                4,
+                [
+                    "/tmp/cached.ledger",
+                ],
+            ),
+            (
+                5,
+                [
+                    "/tmp/other.ledger",
+                ],
+            ),
+        ]
+        "#
+        );
+    }
+
+    #[test]
+    fn test_documents() {
+        let mut index = WorkspaceIndex::default();
+        for (path, source) in [
+            ("/tmp/main.ledger", "include a.ledger\ninclude b.ledger\n"),
+            ("/tmp/a.ledger", "include b.ledger\ninclude main.ledger\n"),
+            ("/tmp/b.ledger", "include missing.ledger\n"),
+        ] {
+            let mut parser = tree_sitter::Parser::new();
+            let tree = parser
+                .set_language(&tree_sitter::Language::new(tree_sitter_ledger::LANGUAGE))
+                .ok()
+                .and_then(|_| parser.parse(source, None));
+            match tree {
+                Some(tree) => {
+                    index.insert(path, IndexedFile::new(path, source.to_string(), tree, &[]))
+                }
+                None => panic!("parsing source"),
+            }
+        }
+
+        let documents = index
+            .documents("/tmp/main.ledger")
+            .map(|document| {
+                (
+                    document.path,
+                    document
+                        .includers
+                        .iter()
+                        .map(|(path, range)| (*path, range.start_point.row))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        insta::assert_debug_snapshot!(documents,
+        @r#"
+        [
+            (
+                "/tmp/main.ledger",
+                [],
+            ),
+            (
+                "/tmp/a.ledger",
+                [
+                    (
+                        "/tmp/main.ledger",
+                        0,
+                    ),
+                ],
+            ),
+            (
+                "/tmp/b.ledger",
+                [
+                    (
+                        "/tmp/main.ledger",
+                        0,
+                    ),
+                    (
+                        "/tmp/a.ledger",
+                        0,
+                    ),
+                ],
+            ),
+        ]
+        "#
+        );
+    }
+}
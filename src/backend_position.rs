@@ -0,0 +1,123 @@
+use tower_lsp::lsp_types::{Position, Range};
+
+/// Converts the columns of a document's positions between bytes, which the
+/// backend uses like tree-sitter does, and UTF-16 code units, which LSP uses
+/// unless the client negotiated the UTF-8 position encoding
+pub struct Columns<'a> {
+    /// The lines of the document, if its columns differ for the client
+    lines: Option<Vec<&'a str>>,
+}
+
+impl<'a> Columns<'a> {
+    /// The columns of the content, for a client that uses UTF-8 positions or
+    /// not. They're the same either way for ASCII content.
+    pub fn new(content: &'a str, utf8: bool) -> Self {
+        let lines = (!utf8 && !content.is_ascii()).then(|| content.split('\n').collect());
+        Self { lines }
+    }
+
+    /// A position from the client, with its column in bytes. A column within
+    /// a character is moved to the end of it.
+    pub fn byte_position(&self, position: Position) -> Position {
+        let line = match self.line(position.line) {
+            Some(line) => line,
+            None => return position,
+        };
+
+        let mut units = 0;
+        for (index, c) in line.char_indices() {
+            if units >= position.character {
+                return Position::new(position.line, index as u32);
+            }
+            units += c.len_utf16() as u32;
+        }
+        // past the end of the line, eg to replace the whole document
+        let past_end = position.character.saturating_sub(units);
+        Position::new(position.line, (line.len() as u32).saturating_add(past_end))
+    }
+
+    /// A position for the client, with its column in the client's encoding. A
+    /// column within a character is moved back to its start.
+    pub fn client_position(&self, position: Position) -> Position {
+        let line = match self.line(position.line) {
+            Some(line) => line,
+            None => return position,
+        };
+
+        let mut column = position.character as usize;
+        if column > line.len() {
+            let units = line.encode_utf16().count() + (column - line.len());
+            return Position::new(position.line, units.min(u32::MAX as usize) as u32);
+        }
+        while !line.is_char_boundary(column) {
+            column -= 1;
+        }
+        Position::new(position.line, line[..column].encode_utf16().count() as u32)
+    }
+
+    /// See `byte_position`
+    pub fn byte_range(&self, range: Range) -> Range {
+        Range::new(
+            self.byte_position(range.start),
+            self.byte_position(range.end),
+        )
+    }
+
+    /// See `client_position`
+    pub fn client_range(&self, range: Range) -> Range {
+        Range::new(
+            self.client_position(range.start),
+            self.client_position(range.end),
+        )
+    }
+
+    fn line(&self, line: u32) -> Option<&'a str> {
+        self.lines.as_ref()?.get(line as usize).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_columns() {
+        let content = "2024/01/01 Café\n    Assets:🏦  1 €\n";
+        let columns = Columns::new(content, false);
+
+        // after "é", "🏦" (a surrogate pair) and "€"
+        let positions = [(0, 16), (1, 15), (1, 22)]
+            .into_iter()
+            .map(|(line, character)| {
+                let client = columns.client_position(Position::new(line, character));
+                (client.character, columns.byte_position(client).character)
+            })
+            .collect::<Vec<_>>();
+        insta::assert_debug_snapshot!(positions, @r"
+        [
+            (
+                15,
+                16,
+            ),
+            (
+                13,
+                15,
+            ),
+            (
+                18,
+                22,
+            ),
+        ]
+        ");
+
+        // UTF-8 clients and ASCII content use bytes
+        let utf8 = Columns::new(content, true).client_position(Position::new(0, 16));
+        let ascii = Columns::new("2024/01/01 Cafe\n", false).client_position(Position::new(0, 15));
+        insta::assert_debug_snapshot!((utf8.character, ascii.character), @r"
+        (
+            16,
+            15,
+        )
+        ");
+    }
+}
@@ -0,0 +1,394 @@
+use tower_lsp::lsp_types::Range as LspRange;
+use tower_lsp::lsp_types::*;
+use tree_sitter::{Node, Tree};
+
+use crate::backend::note_tags;
+use crate::backend_position::Columns;
+
+// indexes into TOKEN_TYPES
+const DATE: u32 = 0;
+const EFFECTIVE_DATE: u32 = 1;
+const STATUS: u32 = 2;
+const CODE: u32 = 3;
+const PAYEE: u32 = 4;
+const ACCOUNT: u32 = 5;
+const COMMODITY: u32 = 6;
+const QUANTITY: u32 = 7;
+const TAG: u32 = 8;
+const KEYWORD: u32 = 9;
+
+const TOKEN_TYPES: [SemanticTokenType; 10] = [
+    SemanticTokenType::new("date"),
+    SemanticTokenType::new("effectiveDate"),
+    SemanticTokenType::new("status"),
+    SemanticTokenType::new("code"),
+    SemanticTokenType::STRING,
+    SemanticTokenType::NAMESPACE,
+    SemanticTokenType::TYPE,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::PROPERTY,
+    SemanticTokenType::KEYWORD,
+];
+
+// bits of TOKEN_MODIFIERS
+const VIRTUAL: u32 = 1 << 0;
+const BALANCED: u32 = 1 << 1;
+const NEGATIVE: u32 = 1 << 2;
+
+const TOKEN_MODIFIERS: [SemanticTokenModifier; 3] = [
+    SemanticTokenModifier::new("virtual"),
+    SemanticTokenModifier::new("balanced"),
+    SemanticTokenModifier::new("negative"),
+];
+
+pub fn legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: TOKEN_TYPES.to_vec(),
+        token_modifiers: TOKEN_MODIFIERS.to_vec(),
+    }
+}
+
+/// A token before delta encoding
+struct Token {
+    line: u32,
+    start: u32,
+    length: u32,
+    token_type: u32,
+    modifiers: u32,
+}
+
+/// Semantic tokens for the document, or for only the nodes that overlap the
+/// given range, with columns converted for the client.
+pub fn semantic_tokens(
+    tree: &Tree,
+    content: &str,
+    range: Option<LspRange>,
+    columns: &Columns,
+) -> Vec<SemanticToken> {
+    let mut tokens = Vec::new();
+    let mut cursor = tree.walk();
+    'nodes: loop {
+        let node = cursor.node();
+        let overlaps = range.is_none_or(|range| {
+            node.end_position().row >= range.start.line as usize
+                && node.start_position().row <= range.end.line as usize
+        });
+
+        if overlaps {
+            tokens_for_node(node, content, &mut tokens);
+            if cursor.goto_first_child() {
+                continue;
+            }
+        }
+
+        // next sibling, or the next sibling of the closest ancestor
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'nodes;
+            }
+        }
+    }
+
+    // tokens are emitted for parents before their children, so sort by
+    // position; overlapping tokens aren't allowed
+    tokens.sort_by_key(|token| (token.line, token.start));
+    tokens.dedup_by(|token, previous| {
+        token.line == previous.line && token.start < previous.start + previous.length
+    });
+    for token in tokens.iter_mut() {
+        let start = columns.client_position(Position::new(token.line, token.start));
+        let end = columns.client_position(Position::new(token.line, token.start + token.length));
+        token.start = start.character;
+        token.length = end.character.saturating_sub(start.character);
+    }
+
+    let mut previous_line = 0;
+    let mut previous_start = 0;
+    tokens
+        .into_iter()
+        .map(|token| {
+            let delta_line = token.line - previous_line;
+            let delta_start = if delta_line == 0 {
+                token.start - previous_start
+            } else {
+                token.start
+            };
+            previous_line = token.line;
+            previous_start = token.start;
+
+            SemanticToken {
+                delta_line,
+                delta_start,
+                length: token.length,
+                token_type: token.token_type,
+                token_modifiers_bitset: token.modifiers,
+            }
+        })
+        .collect()
+}
+
+fn tokens_for_node(node: Node, content: &str, tokens: &mut Vec<Token>) {
+    let text = match content.get(node.start_byte()..node.end_byte()) {
+        Some(text) => text,
+        None => return,
+    };
+    // tokens can't span lines
+    let first_line = text.lines().next().unwrap_or("");
+    let mut push = |offset: usize, length: usize, token_type: u32, modifiers: u32| {
+        if length > 0 {
+            tokens.push(Token {
+                line: node.start_position().row as u32,
+                start: (node.start_position().column + offset) as u32,
+                length: length as u32,
+                token_type,
+                modifiers,
+            });
+        }
+    };
+
+    // the whole node, without surrounding whitespace
+    let trimmed = first_line.trim();
+    let offset = first_line.len() - first_line.trim_start().len();
+
+    match node.kind() {
+        "date" => push(offset, trimmed.len(), DATE, 0),
+        "effective_date" => push(offset, trimmed.len(), EFFECTIVE_DATE, 0),
+        "status" => push(offset, trimmed.len(), STATUS, 0),
+        "code" => push(offset, trimmed.len(), CODE, 0),
+        "payee" => push(offset, trimmed.len(), PAYEE, 0),
+        "account" => {
+            let modifiers = match trimmed.chars().next() {
+                Some('(') => VIRTUAL,
+                Some('[') => VIRTUAL | BALANCED,
+                _ => 0,
+            };
+            push(offset, trimmed.len(), ACCOUNT, modifiers);
+        }
+        "commodity" => push(offset, trimmed.len(), COMMODITY, 0),
+        "quantity" => push(offset, trimmed.len(), QUANTITY, 0),
+        "negative_quantity" => push(offset, trimmed.len(), QUANTITY, NEGATIVE),
+        "note" => {
+            for (offset, tag) in note_tags(first_line) {
+                push(offset, tag.len(), TAG, 0);
+            }
+        }
+        // the first word, eg `account` or `include`
+        "account_directive"
+        | "commodity_directive"
+        | "tag_directive"
+        | "word_directive"
+        | "char_directive"
+        | "option"
+        | "alias_subdirective"
+        | "assert_subdirective"
+        | "check_subdirective"
+        | "default_subdirective"
+        | "format_subdirective"
+        | "note_subdirective" => {
+            let length = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+            push(offset, length, KEYWORD, 0);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_semantic_tokens() {
+        let source = textwrap::dedent(
+            "
+            account Expenses:Food
+
+            2024/01/10 * Grocer
+                Expenses:Food    $25.50  ; :groceries:
+                [Assets:Checking]  25.50 USD
+
+            2024/01/11=2024/01/12 (42) Landlord
+                Expenses:Rent    -500 USD
+            ",
+        );
+        let mut parser = tree_sitter::Parser::new();
+        let tree = parser
+            .set_language(&tree_sitter::Language::new(tree_sitter_ledger::LANGUAGE))
+            .ok()
+            .and_then(|_| parser.parse(&source, None));
+        let tree = match tree {
+            Some(tree) => tree,
+            None => panic!("parsing source"),
+        };
+
+        // (line, start, length, type, modifiers), without delta encoding
+        let decode = |tokens: Vec<SemanticToken>| {
+            let mut line = 0;
+            let mut start = 0;
+            tokens
+                .into_iter()
+                .map(|token| {
+                    if token.delta_line != 0 {
+                        start = 0;
+                    }
+                    line += token.delta_line;
+                    start += token.delta_start;
+                    (
+                        line,
+                        start,
+                        token.length,
+                        TOKEN_TYPES[token.token_type as usize].as_str().to_string(),
+                        token.token_modifiers_bitset,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        insta::assert_debug_snapshot!(decode(semantic_tokens(&tree, &source, None, &Columns::new(&source, true))),
+        @r#"
+        [
+            (
+                1,
+                0,
+                7,
+                "keyword",
+                0,
+            ),
+            (
+                1,
+                8,
+                13,
+                "namespace",
+                0,
+            ),
+            (
+                3,
+                0,
+                10,
+                "date",
+                0,
+            ),
+            (
+                3,
+                11,
+                1,
+                "status",
+                0,
+            ),
+            (
+                3,
+                13,
+                6,
+                "string",
+                0,
+            ),
+            (
+                4,
+                4,
+                13,
+                "namespace",
+                0,
+            ),
+            (
+                4,
+                21,
+                1,
+                "type",
+                0,
+            ),
+            (
+                4,
+                22,
+                5,
+                "number",
+                0,
+            ),
+            (
+                4,
+                32,
+                9,
+                "property",
+                0,
+            ),
+            (
+                5,
+                4,
+                17,
+                "namespace",
+                3,
+            ),
+            (
+                5,
+                23,
+                5,
+                "number",
+                0,
+            ),
+            (
+                5,
+                29,
+                3,
+                "type",
+                0,
+            ),
+            (
+                7,
+                0,
+                10,
+                "date",
+                0,
+            ),
+            (
+                7,
+                10,
+                11,
+                "effectiveDate",
+                0,
+            ),
+            (
+                7,
+                22,
+                4,
+                "code",
+                0,
+            ),
+            (
+                7,
+                27,
+                8,
+                "string",
+                0,
+            ),
+            (
+                8,
+                4,
+                13,
+                "namespace",
+                0,
+            ),
+            (
+                8,
+                21,
+                4,
+                "number",
+                4,
+            ),
+            (
+                8,
+                26,
+                3,
+                "type",
+                0,
+            ),
+        ]
+        "#
+        );
+
+        let range = LspRange {
+            start: Position::new(4, 0),
+            end: Position::new(4, 10),
+        };
+        insta::assert_debug_snapshot!(decode(semantic_tokens(&tree, &source, Some(range), &Columns::new(&source, true))).len(),
+            @"4"
+        );
+    }
+}
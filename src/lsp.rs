@@ -1,9 +1,21 @@
-use crate::backend::{CompletionResult, LedgerBackend, LedgerCompletion, TransactionStatus};
+use crate::backend::{
+    CompletionResult, LedgerBackend, LedgerCompletion, Reference, ReferenceLocation,
+    TransactionStatus, UNKNOWN_ACCOUNT, UNKNOWN_COMMODITY,
+};
+use crate::backend_position::Columns;
+use crate::backend_semantic_tokens;
+use crate::contents_of_path;
 use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, fs};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::task::JoinHandle;
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer};
@@ -20,11 +32,17 @@ where
 {
     let (service, socket) = LspService::new(|client| Lsp {
         client,
-        state: Mutex::new(LspState {
+        state: Arc::new(Mutex::new(LspState {
             backend: LedgerBackend::new(),
             config: Config::default(),
-            sources: HashMap::new(),
-        }),
+            workspace_root: None,
+            utf8_positions: false,
+            pending_diagnostics: HashMap::new(),
+            published: HashSet::new(),
+            watch_files: false,
+            watched: None,
+            watched_generation: None,
+        })),
     });
     Server::new(read, write, socket).serve(service).await;
 }
@@ -34,15 +52,42 @@ pub struct LspState {
 
     pub config: Config,
 
-    // see https://github.com/ebkalderon/nix-language-server/blob/master/src/backend.rs#L14-L23
-    /// Mapping of path names to file contents.
-    pub sources: HashMap<String, String>,
+    /// The first workspace folder, which the root journal is relative to
+    pub workspace_root: Option<PathBuf>,
+
+    /// Whether the client's columns are bytes, rather than UTF-16 code units
+    pub utf8_positions: bool,
+
+    /// Mapping of path names to the task that will publish their diagnostics,
+    /// once the document stops changing.
+    pub pending_diagnostics: HashMap<String, JoinHandle<()>>,
+
+    /// Paths of the documents that diagnostics were last published for
+    pub published: HashSet<String>,
+
+    /// Whether the client can watch the files that we ask it to
+    pub watch_files: bool,
+
+    /// Paths of the files the client was last asked to watch, along with
+    /// `**/*.ledger`
+    pub watched: Option<Vec<String>>,
+
+    /// `LedgerBackend::include_graph_generation` when the watched paths were
+    /// last computed
+    pub watched_generation: Option<u64>,
 }
 
 #[derive(Debug)]
 pub struct Config {
     pub format: bool,
     pub format_sort_transactions: bool,
+    pub rename_subaccounts: bool,
+    pub running_balance_accounts: Vec<String>,
+    pub strict: bool,
+    pub accounts_file: Option<String>,
+    pub diagnostics_on_change: bool,
+    pub diagnostics_delay: Duration,
+    pub root_journal: Option<String>,
 }
 
 impl Default for Config {
@@ -50,13 +95,448 @@ impl Default for Config {
         Self {
             format: true,
             format_sort_transactions: true,
+            rename_subaccounts: true,
+            running_balance_accounts: Vec::new(),
+            strict: false,
+            accounts_file: None,
+            diagnostics_on_change: true,
+            diagnostics_delay: Duration::from_millis(300),
+            root_journal: None,
+        }
+    }
+}
+
+/// The path of a setting, with a leading `~/` expanded and relative to `dir`
+/// (if any)
+fn resolve_path(path: &str, dir: Option<&Path>) -> PathBuf {
+    let path = match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(path), Some(home)) => PathBuf::from(home).join(path),
+        (_, _) => PathBuf::from(path),
+    };
+    match dir {
+        Some(dir) if path.is_relative() => dir.join(path),
+        Some(_) | None => path,
+    }
+}
+
+/// The root journal: the `root_journal` setting, otherwise `$LEDGER_FILE` or
+/// the `--file` option of `./.ledgerrc` or `~/.ledgerrc`, like ledger. With a
+/// workspace, those are only used for journals in it, since they may well
+/// belong to another project. Relative paths are relative to the workspace (or
+/// the `.ledgerrc`).
+fn root_journal(setting: Option<&str>, workspace_root: Option<&Path>) -> Option<PathBuf> {
+    if let Some(setting) = setting {
+        return Some(resolve_path(setting, workspace_root));
+    }
+
+    let ledger_file = env::var("LEDGER_FILE")
+        .ok()
+        .filter(|ledger_file| !ledger_file.is_empty())
+        .map(|ledger_file| resolve_path(&ledger_file, workspace_root));
+    ledger_file
+        .into_iter()
+        .chain(
+            [
+                workspace_root.map(Path::to_path_buf),
+                env::var_os("HOME").map(PathBuf::from),
+            ]
+            .into_iter()
+            .flatten()
+            .filter_map(|dir| {
+                let ledgerrc = fs::read_to_string(dir.join(".ledgerrc")).ok()?;
+                ledgerrc_file(&ledgerrc).map(|file| resolve_path(file, Some(&dir)))
+            }),
+        )
+        .find(|path| workspace_root.is_none_or(|workspace_root| path.starts_with(workspace_root)))
+}
+
+/// The journal given by the `--file` (or `-f`) option of a `.ledgerrc`
+fn ledgerrc_file(ledgerrc: &str) -> Option<&str> {
+    ledgerrc
+        .lines()
+        .find_map(|line| match line.trim().split_once(['=', ' ', '\t']) {
+            Some(("--file" | "-f", file)) => Some(file.trim()).filter(|file| !file.is_empty()),
+            Some(_) | None => None,
+        })
+}
+
+/// The document symbol and its children, with ranges for the client
+fn client_symbol(columns: &Columns, symbol: DocumentSymbol) -> DocumentSymbol {
+    DocumentSymbol {
+        range: columns.client_range(symbol.range),
+        selection_range: columns.client_range(symbol.selection_range),
+        children: symbol.children.map(|children| {
+            children
+                .into_iter()
+                .map(|child| client_symbol(columns, child))
+                .collect()
+        }),
+        ..symbol
+    }
+}
+
+/// An edit appending a line to the end of the content, after a newline if
+/// the content doesn't end with one
+fn append_edit(content: &str, line: String) -> TextEdit {
+    let lines = content.lines().count() as u32;
+    match content.lines().last() {
+        Some(last) if !content.ends_with('\n') => {
+            let end = Position::new(lines - 1, last.len() as u32);
+            TextEdit::new(Range::new(end, end), format!("\n{line}"))
+        }
+        Some(_) | None => {
+            let end = Position::new(lines, 0);
+            TextEdit::new(Range::new(end, end), line)
+        }
+    }
+}
+
+impl LspState {
+    /// The columns of an indexed document, see `Columns`
+    fn columns(&self, path: &str) -> Columns<'_> {
+        let content = self.backend.indexed_content(path).unwrap_or_default();
+        Columns::new(content, self.utf8_positions)
+    }
+
+    /// Diagnostics for every document in the include graph of the root
+    /// journal, by path, including the checks enabled by the config. Checks
+    /// that fail are logged and skipped.
+    async fn journal_diagnostics(
+        &mut self,
+        client: &Client,
+        root_path: &str,
+        root_content: &str,
+    ) -> HashMap<String, Vec<Diagnostic>> {
+        let mut visited = HashSet::new();
+        let documents = self
+            .backend
+            .journal_documents(root_path, root_content, &mut visited);
+
+        let declared = if self.config.strict {
+            let mut visited = HashSet::new();
+            let accounts = self
+                .backend
+                .declared_accounts(root_path, root_content, &mut visited);
+            let accounts = unwrap_or_log(client, accounts).await;
+            let mut visited = HashSet::new();
+            let commodities =
+                self.backend
+                    .declared_commodities(root_path, root_content, &mut visited);
+            let commodities = unwrap_or_log(client, commodities).await;
+            Some((accounts, commodities))
+        } else {
+            None
+        };
+
+        let mut diagnostics = HashMap::new();
+        // missing includes aren't in the graph, they're reported by the
+        // document that includes them
+        for (path, content) in documents {
+            let mut document_diagnostics =
+                unwrap_or_log(client, self.backend.diagnostics(&path, &content)).await;
+            document_diagnostics.extend(self.backend.parse_error_diagnostics(&path, &content));
+            if let Some((ref accounts, ref commodities)) = declared {
+                let undeclared_accounts = self
+                    .backend
+                    .undeclared_account_diagnostics(&path, &content, accounts);
+                document_diagnostics.extend(unwrap_or_log(client, undeclared_accounts).await);
+                let undeclared_commodities =
+                    self.backend
+                        .undeclared_commodity_diagnostics(&path, &content, commodities);
+                document_diagnostics.extend(unwrap_or_log(client, undeclared_commodities).await);
+            }
+            diagnostics.insert(path, document_diagnostics);
+        }
+
+        let mut visited = HashSet::new();
+        let balance_assertion_diagnostics =
+            self.backend
+                .balance_assertion_diagnostics(root_path, root_content, &mut visited);
+        let balance_assertion_diagnostics =
+            unwrap_or_log(client, balance_assertion_diagnostics).await;
+        let include_graph_diagnostics = self
+            .backend
+            .include_graph_diagnostics(root_path, root_content);
+        let include_graph_diagnostics = unwrap_or_log(client, include_graph_diagnostics).await;
+        for (path, diagnostic) in include_graph_diagnostics
+            .into_iter()
+            .chain(balance_assertion_diagnostics)
+        {
+            if let Some(document_diagnostics) = diagnostics.get_mut(&path) {
+                document_diagnostics.push(diagnostic);
+            }
+        }
+
+        for (path, document_diagnostics) in diagnostics.iter_mut() {
+            let columns = self.columns(path);
+            for diagnostic in document_diagnostics.iter_mut() {
+                diagnostic.range = columns.client_range(diagnostic.range);
+            }
+        }
+
+        diagnostics
+    }
+
+    /// The path and content of the root journal, if any
+    fn root_document(&mut self) -> Option<(String, String)> {
+        let root = self.backend.root_journal()?.to_string();
+        let content = match self.backend.document(&root) {
+            Some(content) => content.to_string(),
+            None => contents_of_path(&root).unwrap_or_default(),
+        };
+        self.backend.parse_document(&root, &content);
+        Some((root, content))
+    }
+
+    /// Paths of every file in the include graphs of the root journal and the
+    /// open documents, including those that don't exist (yet), sorted
+    fn include_graph_paths(&mut self) -> Vec<String> {
+        let mut documents = self.backend.open_documents();
+        documents.extend(self.root_document());
+
+        let mut paths = HashSet::new();
+        for (path, content) in documents {
+            if !paths.contains(&path) {
+                paths.extend(self.backend.journal_paths(&path, &content));
+            }
+        }
+
+        let mut paths = paths.into_iter().collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    /// Diagnostics for every document in the include graphs of the root
+    /// journal and the open documents, by path. Open documents that aren't
+    /// included by another open document are also root journals.
+    async fn workspace_diagnostics(&mut self, client: &Client) -> HashMap<String, Vec<Diagnostic>> {
+        let sources = self.backend.open_documents();
+
+        let mut included = HashSet::new();
+        for (path, content) in sources.iter() {
+            included.extend(
+                self.backend
+                    .journal_documents(path, content, &mut HashSet::new())
+                    .into_iter()
+                    .map(|(included_path, _)| included_path)
+                    .filter(|included_path| included_path != path),
+            );
+        }
+
+        // roots first, then any open documents that weren't reached from a
+        // root, eg because they include each other
+        let (roots, others): (Vec<_>, Vec<_>) = sources
+            .into_iter()
+            .partition(|(path, _)| !included.contains(path));
+
+        // the documents included by the root journal are checked in its
+        // context, even if an open document includes them too
+        let root = self.root_document();
+
+        let mut diagnostics = HashMap::new();
+        for (path, content) in root.into_iter().chain(roots).chain(others) {
+            if diagnostics.contains_key(&path) {
+                continue;
+            }
+            for (path, document_diagnostics) in
+                self.journal_diagnostics(client, &path, &content).await
+            {
+                diagnostics.entry(path).or_insert(document_diagnostics);
+            }
+        }
+
+        diagnostics
+    }
+
+    /// Diagnostics for a document, as part of the include graph of an open
+    /// document, or as a root journal itself if it isn't in one. Only the
+    /// journal that `workspace_diagnostics` would check it in is checked.
+    async fn diagnostics(
+        &mut self,
+        client: &Client,
+        buffer_path: &str,
+        content: &str,
+    ) -> Vec<Diagnostic> {
+        let root = self
+            .root_document()
+            .filter(|(path, content)| self.backend.journal_includes(path, content, buffer_path));
+
+        let mut journals = self.backend.open_documents();
+        journals
+            .retain(|(path, content)| self.backend.journal_includes(path, content, buffer_path));
+        // journals included by another journal are only used if they include
+        // each other
+        let (roots, others): (Vec<_>, Vec<_>) = journals.iter().cloned().partition(|(path, _)| {
+            !journals.iter().any(|(other, other_content)| {
+                other != path && self.backend.journal_includes(other, other_content, path)
+            })
+        });
+
+        let (path, content) = root
+            .into_iter()
+            .chain(roots)
+            .chain(others)
+            .next()
+            .unwrap_or_else(|| (buffer_path.to_string(), content.to_string()));
+        self.journal_diagnostics(client, &path, &content)
+            .await
+            .remove(buffer_path)
+            .unwrap_or_default()
+    }
+
+    /// Diagnostics to publish, for every document in the include graphs of the
+    /// open documents. Documents that were published previously but no longer
+    /// are in a graph are published without diagnostics, to clear them.
+    async fn diagnostics_to_publish(
+        &mut self,
+        client: &Client,
+    ) -> Vec<(Url, Vec<Diagnostic>, Option<i32>)> {
+        let mut diagnostics = self.workspace_diagnostics(client).await;
+        let published = diagnostics.keys().cloned().collect::<HashSet<_>>();
+        for path in self.published.difference(&published) {
+            diagnostics.insert(path.clone(), Vec::new());
+        }
+        self.published = published;
+
+        let mut diagnostics = diagnostics.into_iter().collect::<Vec<_>>();
+        diagnostics.sort_by(|(a, _), (b, _)| a.cmp(b));
+        diagnostics
+            .into_iter()
+            .filter_map(|(path, diagnostics)| {
+                let version = self.backend.document_version(&path);
+                Url::from_file_path(&path)
+                    .ok()
+                    .map(|uri| (uri, diagnostics, version))
+            })
+            .collect()
+    }
+}
+
+/// The value, or the default after logging the error
+async fn unwrap_or_log<T: Default>(client: &Client, result: anyhow::Result<T>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => {
+            client
+                .log_message(MessageType::ERROR, format!("[diagnostics] {err}"))
+                .await;
+            T::default()
+        }
+    }
+}
+
+/// Publish diagnostics for every document in the include graphs of the open
+/// documents. The lock is released while they're sent to the client.
+async fn publish_diagnostics(
+    client: &Client,
+    lock: &Mutex<LspState>,
+    mut state: MutexGuard<'_, LspState>,
+) {
+    let diagnostics = state.diagnostics_to_publish(client).await;
+    drop(state);
+    for (uri, diagnostics, version) in diagnostics {
+        client.publish_diagnostics(uri, diagnostics, version).await;
+    }
+
+    // the include graphs may have changed
+    watch_files(client, lock).await;
+}
+
+/// Registration id for watching files, see `watch_files`
+const WATCHED_FILES: &str = "watched-files";
+
+/// Ask the client to watch `**/*.ledger` and every file in the include graphs,
+/// unless it's already watching them. The paths are only computed again once
+/// the include graphs change, and the lock is released while (un)registering.
+/// Changes are handled by `did_change_watched_files`.
+async fn watch_files(client: &Client, lock: &Mutex<LspState>) {
+    let (paths, registered) = {
+        let mut state = lock.lock().await;
+        if !state.watch_files
+            || state.watched_generation == Some(state.backend.include_graph_generation())
+        {
+            return;
+        }
+        let paths = state.include_graph_paths();
+        // computing the paths may index included documents
+        state.watched_generation = Some(state.backend.include_graph_generation());
+        if state.watched.as_ref() == Some(&paths) {
+            return;
         }
+        let registered = state.watched.replace(paths.clone()).is_some();
+        (paths, registered)
+    };
+
+    let method = "workspace/didChangeWatchedFiles".to_string();
+    if registered {
+        let unregistration = Unregistration {
+            id: WATCHED_FILES.to_string(),
+            method: method.clone(),
+        };
+        if let Err(err) = client.unregister_capability(vec![unregistration]).await {
+            client
+                .log_message(MessageType::ERROR, format!("[watch_files] {err}"))
+                .await;
+        }
+    }
+
+    let watchers = ["**/*.ledger".to_string()]
+        .into_iter()
+        .chain(paths)
+        .map(|path| FileSystemWatcher {
+            glob_pattern: GlobPattern::String(path),
+            kind: None,
+        })
+        .collect();
+    let registration = Registration {
+        id: WATCHED_FILES.to_string(),
+        method,
+        register_options: serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
+            watchers,
+        })
+        .ok(),
+    };
+    if let Err(err) = client.register_capability(vec![registration]).await {
+        let mut state = lock.lock().await;
+        state.watched = None;
+        state.watched_generation = None;
+        drop(state);
+        client
+            .log_message(MessageType::ERROR, format!("[watch_files] {err}"))
+            .await;
     }
 }
 
+/// Diagnostics for a document, for the pull model; unchanged if they're the
+/// same as the previous result.
+fn diagnostic_report(
+    diagnostics: Vec<Diagnostic>,
+    previous_result_id: Option<&str>,
+) -> DocumentDiagnosticReportKind {
+    let result_id = result_id(&diagnostics);
+    if previous_result_id == Some(result_id.as_str()) {
+        DocumentDiagnosticReportKind::Unchanged(UnchangedDocumentDiagnosticReport { result_id })
+    } else {
+        DocumentDiagnosticReportKind::Full(FullDocumentDiagnosticReport {
+            result_id: Some(result_id),
+            items: diagnostics,
+        })
+    }
+}
+
+/// Identifies a set of diagnostics, so that clients pulling diagnostics can be
+/// told that they're unchanged.
+fn result_id(diagnostics: &[Diagnostic]) -> String {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(diagnostics)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
 pub struct Lsp {
     pub client: Client,
-    pub state: Mutex<LspState>,
+    pub state: Arc<Mutex<LspState>>,
 }
 
 macro_rules! log {
@@ -95,6 +575,35 @@ macro_rules! log_debug {
     });
 }
 
+impl Lsp {
+    /// Resolve the root journal from the config and environment, see
+    /// `root_journal`
+    async fn resolve_root_journal(&self, state: &mut LspState) {
+        let root = match root_journal(
+            state.config.root_journal.as_deref(),
+            state.workspace_root.as_deref(),
+        ) {
+            Some(root) if root.is_file() => {
+                log!(self, INFO, "[root_journal] {}", root.display());
+                Some(root.to_string_lossy().to_string())
+            }
+            Some(root) => {
+                log!(
+                    self,
+                    WARNING,
+                    "[root_journal] root journal {} does not exist",
+                    root.display()
+                );
+                None
+            }
+            None => None,
+        };
+        if state.backend.root_journal() != root.as_deref() {
+            state.backend.set_root_journal(root);
+        }
+    }
+}
+
 #[tower_lsp::async_trait]
 impl LanguageServer for Lsp {
     async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
@@ -118,6 +627,13 @@ impl LanguageServer for Lsp {
         );
 
         let mut state = self.state.lock().await;
+        state.watch_files = params
+            .capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files)
+            .and_then(|watched_files| watched_files.dynamic_registration)
+            .unwrap_or(false);
         if let Some(ref opts) = params.initialization_options {
             match opts.get("formatting") {
                 Some(Value::Bool(should_format)) => {
@@ -138,9 +654,105 @@ impl LanguageServer for Lsp {
                 }
                 None => {}
             }
+
+            match opts.get("running_balance_accounts") {
+                Some(Value::Array(accounts))
+                    if accounts.iter().all(|account| account.is_string()) =>
+                {
+                    state.config.running_balance_accounts = accounts
+                        .iter()
+                        .filter_map(|account| account.as_str().map(str::to_string))
+                        .collect();
+                }
+                Some(_) => {
+                    log!(self, WARNING, "[initialize:config] unrecognized value for lsp setting 'running_balance_accounts'. Expected a list of account names.");
+                }
+                None => {}
+            }
+
+            match opts.get("strict") {
+                Some(Value::Bool(strict)) => {
+                    state.config.strict = *strict;
+                }
+                Some(_) => {
+                    log!(self, WARNING, "[initialize:config] unrecognized value for lsp setting 'strict'. Expected one of `true` or `false`.");
+                }
+                None => {}
+            }
+
+            match opts.get("accounts_file") {
+                Some(Value::String(accounts_file)) => {
+                    state.config.accounts_file = Some(accounts_file.clone());
+                }
+                Some(_) => {
+                    log!(self, WARNING, "[initialize:config] unrecognized value for lsp setting 'accounts_file'. Expected a path.");
+                }
+                None => {}
+            }
+
+            match opts.get("diagnostics_on_change") {
+                Some(Value::Bool(on_change)) => {
+                    state.config.diagnostics_on_change = *on_change;
+                }
+                Some(_) => {
+                    log!(self, WARNING, "[initialize:config] unrecognized value for lsp setting 'diagnostics_on_change'. Expected one of `true` or `false`.");
+                }
+                None => {}
+            }
+
+            match opts.get("diagnostics_delay").map(Value::as_u64) {
+                Some(Some(delay)) => {
+                    state.config.diagnostics_delay = Duration::from_millis(delay);
+                }
+                Some(None) => {
+                    log!(self, WARNING, "[initialize:config] unrecognized value for lsp setting 'diagnostics_delay'. Expected a number of milliseconds.");
+                }
+                None => {}
+            }
+
+            match opts.get("root_journal").or_else(|| opts.get("rootJournal")) {
+                Some(Value::String(root_journal)) => {
+                    state.config.root_journal = Some(root_journal.clone());
+                }
+                Some(_) => {
+                    log!(self, WARNING, "[initialize:config] unrecognized value for lsp setting 'root_journal'. Expected a path.");
+                }
+                None => {}
+            }
+
+            match opts.get("rename_subaccounts") {
+                Some(Value::Bool(should_rename)) => {
+                    state.config.rename_subaccounts = *should_rename;
+                }
+                Some(_) => {
+                    log!(self, WARNING, "[initialize:config] unrecognized value for lsp setting 'rename_subaccounts'. Expected one of `true` or `false`.");
+                }
+                None => {}
+            }
         }
         log_debug!(self, "[initialize:config] {:#?}", state.config);
 
+        #[allow(deprecated)]
+        let workspace_root = params
+            .workspace_folders
+            .as_ref()
+            .and_then(|folders| folders.first())
+            .map(|folder| &folder.uri)
+            .or(params.root_uri.as_ref())
+            .and_then(|uri| uri.to_file_path().ok());
+        state.workspace_root = workspace_root;
+        self.resolve_root_journal(&mut state).await;
+
+        // the backend's columns are bytes, like tree-sitter's; positions are
+        // converted for clients that use UTF-16 code units
+        let utf8 = params
+            .capabilities
+            .general
+            .as_ref()
+            .and_then(|general| general.position_encodings.as_ref())
+            .is_some_and(|encodings| encodings.contains(&PositionEncodingKind::UTF8));
+        state.utf8_positions = utf8;
+
         Ok(InitializeResult {
             server_info: None,
             capabilities: ServerCapabilities {
@@ -156,14 +768,43 @@ impl LanguageServer for Lsp {
                 document_formatting_provider: Some(OneOf::Left(true))
                     .filter(|_| state.config.format),
                 definition_provider: Some(OneOf::Left(true)),
+                diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
+                    DiagnosticOptions {
+                        identifier: Some("ledger".to_string()),
+                        inter_file_dependencies: true,
+                        workspace_diagnostics: true,
+                        ..DiagnosticOptions::default()
+                    },
+                )),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                inlay_hint_provider: Some(OneOf::Left(true)),
+                references_provider: Some(OneOf::Left(true)),
+                semantic_tokens_provider: Some(
+                    SemanticTokensServerCapabilities::SemanticTokensOptions(
+                        SemanticTokensOptions {
+                            legend: backend_semantic_tokens::legend(),
+                            range: Some(true),
+                            full: Some(SemanticTokensFullOptions::Bool(true)),
+                            ..SemanticTokensOptions::default()
+                        },
+                    ),
+                ),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: Default::default(),
+                })),
                 execute_command_provider: None,
                 // execute_command_provider: Some(ExecuteCommandOptions {
                 //     commands: vec!["dummy.do_something".to_string()],
                 //     work_done_progress_options: Default::default(),
                 // }),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
+                    TextDocumentSyncKind::INCREMENTAL,
                 )),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                position_encoding: Some(PositionEncodingKind::UTF8).filter(|_| utf8),
                 workspace: Some(WorkspaceServerCapabilities {
                     workspace_folders: Some(WorkspaceFoldersServerCapabilities {
                         supported: Some(true),
@@ -179,6 +820,8 @@ impl LanguageServer for Lsp {
 
     async fn initialized(&self, _params: InitializedParams) {
         log_debug!(self, "[initialized] {_params:?}");
+
+        watch_files(&self.client, &self.state).await;
     }
 
     async fn shutdown(&self) -> Result<()> {
@@ -190,12 +833,50 @@ impl LanguageServer for Lsp {
         log_debug!(self, "[did_change_workspace_folders] {_params:?}");
     }
 
-    async fn did_change_configuration(&self, _params: DidChangeConfigurationParams) {
-        log_debug!(self, "[did_change_configuration] {_params:?}");
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        log_debug!(self, "[did_change_configuration] {params:?}");
+
+        let mut state = self.state.lock().await;
+        let settings = &params.settings;
+        match settings
+            .get("root_journal")
+            .or_else(|| settings.get("rootJournal"))
+        {
+            Some(Value::String(root_journal)) => {
+                state.config.root_journal = Some(root_journal.clone());
+            }
+            Some(Value::Null) => {
+                state.config.root_journal = None;
+            }
+            Some(_) => {
+                log!(self, WARNING, "[did_change_configuration] unrecognized value for lsp setting 'root_journal'. Expected a path.");
+            }
+            None => {}
+        }
+
+        // `$LEDGER_FILE` or a `.ledgerrc` may have changed too
+        self.resolve_root_journal(&mut state).await;
+        state.backend.evict();
+        publish_diagnostics(&self.client, &self.state, state).await;
     }
 
-    async fn did_change_watched_files(&self, _params: DidChangeWatchedFilesParams) {
-        log_debug!(self, "[did_change_watched_files] {_params:?}");
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        log_debug!(self, "[did_change_watched_files] {params:?}");
+
+        let mut state = self.state.lock().await;
+        for change in params.changes.iter() {
+            state.backend.reload_document(change.uri.path());
+        }
+        if params
+            .changes
+            .iter()
+            .any(|change| change.typ != FileChangeType::CHANGED)
+        {
+            state.backend.refresh_includes();
+        }
+
+        // eg an import script appended to an included file
+        publish_diagnostics(&self.client, &self.state, state).await;
     }
 
     async fn execute_command(&self, _params: ExecuteCommandParams) -> Result<Option<Value>> {
@@ -220,22 +901,14 @@ impl LanguageServer for Lsp {
         // on open, cache the file contents, generate initial completions, and
         // run dianostics
         let mut state = self.state.lock().await;
-        state.sources.insert(
-            params.text_document.uri.path().to_owned(),
-            params.text_document.text.clone(),
+        state.backend.open_document(
+            params.text_document.uri.path(),
+            params.text_document.version,
+            &params.text_document.text,
         );
-        state.backend.parse_document(&params.text_document.text);
+        state.backend.evict();
 
-        self.client
-            .publish_diagnostics(
-                params.text_document.uri.clone(),
-                LedgerBackend::diagnostics(
-                    params.text_document.uri.path(),
-                    &params.text_document.text,
-                ),
-                None,
-            )
-            .await;
+        publish_diagnostics(&self.client, &self.state, state).await;
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
@@ -252,18 +925,47 @@ impl LanguageServer for Lsp {
             log_debug!(self, "[did_change] {p:?}");
         }
 
-        // on update, only cache the file contents and don't touch the
-        // completions or diagnostics (because the buffer may be
-        // dirty/incomplete/incorrect)
+        // on update, cache the file contents, and refresh the diagnostics
+        // after a delay (because the buffer may be mid-edit)
         let mut state = self.state.lock().await;
-        let content = match params.content_changes.first() {
-            Some(content) => content.text.clone(),
-            None => String::new(),
-        };
-        state
-            .sources
-            .insert(params.text_document.uri.path().to_owned(), content.clone());
-        state.backend.parse_document(&content);
+        let path = params.text_document.uri.path();
+        let utf8 = state.utf8_positions;
+        if let Err(err) = state.backend.update_document(
+            path,
+            params.text_document.version,
+            &params.content_changes,
+            utf8,
+        ) {
+            log!(self, ERROR, "[did_change] {err}");
+            return;
+        }
+
+        // publish diagnostics once the buffer settles. Diagnostics for an
+        // older version are cancelled while they wait out the delay (or the
+        // lock); once they're computed they're no longer pending
+        if let Some(pending) = state.pending_diagnostics.remove(path) {
+            pending.abort();
+        }
+        if !state.config.diagnostics_on_change {
+            return;
+        }
+
+        let client = self.client.clone();
+        let lock = self.state.clone();
+        let delay = state.config.diagnostics_delay;
+        let uri = params.text_document.uri.clone();
+        let version = params.text_document.version;
+        let pending = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+
+            let mut state = lock.lock().await;
+            if state.backend.document_version(uri.path()) != Some(version) {
+                return;
+            }
+            state.pending_diagnostics.remove(uri.path());
+            publish_diagnostics(&client, &lock, state).await;
+        });
+        state.pending_diagnostics.insert(path.to_owned(), pending);
     }
 
     async fn did_save(&self, params: DidSaveTextDocumentParams) {
@@ -277,20 +979,137 @@ impl LanguageServer for Lsp {
         // the file contents (params don't have access to updated buffer contents)
         // TODO figure out how to send TextDocumentSaveRegistrationOptions{include_text: Some(true)}
         // ... then we could update both
-        let state = self.state.lock().await;
-        if let Some(content) = state.sources.get(params.text_document.uri.path()).cloned() {
-            self.client
-                .publish_diagnostics(
-                    params.text_document.uri.clone(),
-                    LedgerBackend::diagnostics(params.text_document.uri.path(), &content),
-                    None,
-                )
-                .await;
+        let mut state = self.state.lock().await;
+        // diagnostics are published now, rather than after the delay
+        if let Some(pending) = state
+            .pending_diagnostics
+            .remove(params.text_document.uri.path())
+        {
+            pending.abort();
+        }
+        // includes may have changed
+        state.backend.evict();
+        publish_diagnostics(&self.client, &self.state, state).await;
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        log_debug!(self, "[did_close] {params:?}");
+
+        let mut state = self.state.lock().await;
+        if let Some(pending) = state
+            .pending_diagnostics
+            .remove(params.text_document.uri.path())
+        {
+            pending.abort();
         }
+        state
+            .backend
+            .close_document(params.text_document.uri.path());
+        state.backend.evict();
+
+        // the documents it included may no longer be in an include graph
+        publish_diagnostics(&self.client, &self.state, state).await;
+    }
+
+    async fn diagnostic(
+        &self,
+        params: DocumentDiagnosticParams,
+    ) -> Result<DocumentDiagnosticReportResult> {
+        log_debug!(self, "[diagnostic] {params:?}");
+
+        let mut state = self.state.lock().await;
+        let path = params.text_document.uri.path();
+        let content = match state.backend.document(path) {
+            Some(content) => content.to_string(),
+            None => match contents_of_path(path) {
+                Ok(content) => {
+                    state.backend.parse_document(path, &content);
+                    content
+                }
+                Err(err) => {
+                    log!(self, ERROR, "[diagnostic] {err}");
+                    String::new()
+                }
+            },
+        };
+
+        let diagnostics = state.diagnostics(&self.client, path, &content).await;
+        state.backend.evict();
+
+        let report = match diagnostic_report(diagnostics, params.previous_result_id.as_deref()) {
+            DocumentDiagnosticReportKind::Full(report) => {
+                DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport {
+                    related_documents: None,
+                    full_document_diagnostic_report: report,
+                })
+            }
+            DocumentDiagnosticReportKind::Unchanged(report) => {
+                DocumentDiagnosticReport::Unchanged(RelatedUnchangedDocumentDiagnosticReport {
+                    related_documents: None,
+                    unchanged_document_diagnostic_report: report,
+                })
+            }
+        };
+
+        Ok(DocumentDiagnosticReportResult::Report(report))
     }
 
-    async fn did_close(&self, _params: DidCloseTextDocumentParams) {
-        log_debug!(self, "[did_close] {_params:?}");
+    async fn workspace_diagnostic(
+        &self,
+        params: WorkspaceDiagnosticParams,
+    ) -> Result<WorkspaceDiagnosticReportResult> {
+        log_debug!(self, "[workspace_diagnostic] {params:?}");
+
+        let mut state = self.state.lock().await;
+        let previous_result_ids = params
+            .previous_result_ids
+            .into_iter()
+            .map(|previous| (previous.uri, previous.value))
+            .collect::<HashMap<_, _>>();
+        // open files, and every file they include
+        let mut diagnostics = state
+            .workspace_diagnostics(&self.client)
+            .await
+            .into_iter()
+            .collect::<Vec<_>>();
+        diagnostics.sort_by(|(a, _), (b, _)| a.cmp(b));
+        state.backend.evict();
+
+        let mut items = Vec::new();
+        for (path, diagnostics) in diagnostics {
+            let Ok(uri) = Url::from_file_path(&path) else {
+                log!(
+                    self,
+                    ERROR,
+                    "[workspace_diagnostic] Unable to build url for {path}"
+                );
+                continue;
+            };
+            let version = state.backend.document_version(&path).map(i64::from);
+            let previous_result_id = previous_result_ids.get(&uri).map(String::as_str);
+            items.push(match diagnostic_report(diagnostics, previous_result_id) {
+                DocumentDiagnosticReportKind::Full(report) => {
+                    WorkspaceDocumentDiagnosticReport::Full(WorkspaceFullDocumentDiagnosticReport {
+                        uri,
+                        version,
+                        full_document_diagnostic_report: report,
+                    })
+                }
+                DocumentDiagnosticReportKind::Unchanged(report) => {
+                    WorkspaceDocumentDiagnosticReport::Unchanged(
+                        WorkspaceUnchangedDocumentDiagnosticReport {
+                            uri,
+                            version,
+                            unchanged_document_diagnostic_report: report,
+                        },
+                    )
+                }
+            });
+        }
+
+        Ok(WorkspaceDiagnosticReportResult::Report(
+            WorkspaceDiagnosticReport { items },
+        ))
     }
 
     async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
@@ -304,17 +1123,20 @@ impl LanguageServer for Lsp {
             start_time.elapsed()
         );
         let pathname = params.text_document.uri.path();
-        let contents = match state.sources.get(pathname) {
-            Some(contents) => contents.clone(),
+        let contents = match state.backend.document(pathname) {
+            Some(contents) => contents.to_string(),
             None => return Ok(None),
         };
+        let utf8 = state.utf8_positions;
+        let columns = Columns::new(&contents, utf8);
 
         let mut actions = Vec::new();
 
-        match state
-            .backend
-            .transaction_at_position_status(&contents, &params.range.start)
-        {
+        match state.backend.transaction_at_position_status(
+            pathname,
+            &contents,
+            &columns.byte_position(params.range.start),
+        ) {
             Ok(Some(status)) => {
                 let make_pending_edit = |range| {
                     (
@@ -365,6 +1187,7 @@ impl LanguageServer for Lsp {
 
                 match status {
                     TransactionStatus::NotCleared(pos) => {
+                        let pos = columns.client_position(pos);
                         let range = Range {
                             start: pos,
                             end: pos,
@@ -374,10 +1197,12 @@ impl LanguageServer for Lsp {
                         actions.push(make_code_action(make_cleared_edit(range)));
                     }
                     TransactionStatus::Pending(range) => {
+                        let range = columns.client_range(range);
                         actions.push(make_code_action(make_cleared_edit(range)));
                         actions.push(make_code_action(make_not_cleared_edit(range)));
                     }
                     TransactionStatus::Cleared(range) => {
+                        let range = columns.client_range(range);
                         actions.push(make_code_action(make_pending_edit(range)));
                         actions.push(make_code_action(make_not_cleared_edit(range)));
                     }
@@ -390,12 +1215,15 @@ impl LanguageServer for Lsp {
             }
         };
 
-        match state.backend.pending_transaction_status_ranges(&contents) {
+        match state
+            .backend
+            .pending_transaction_status_ranges(pathname, &contents)
+        {
             Ok(pending_ranges) if !pending_ranges.is_empty() => {
                 let pending_edits = pending_ranges
                     .into_iter()
                     .map(|range| TextEdit {
-                        range,
+                        range: columns.client_range(range),
                         new_text: "*".to_string(),
                     })
                     .collect();
@@ -424,7 +1252,170 @@ impl LanguageServer for Lsp {
             }
         };
 
-        log!(self, "[code_action:response] @ {:?}", start_time.elapsed());
+        let make_quick_fix = |title: String,
+                              diagnostic: &Diagnostic,
+                              uri: Url,
+                              edit: TextEdit,
+                              is_preferred: bool| {
+            let mut changes = HashMap::new();
+            changes.insert(uri, vec![edit]);
+
+            CodeActionOrCommand::CodeAction(CodeAction {
+                title,
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic.clone()]),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(changes),
+                    document_changes: None,
+                    change_annotations: None,
+                }),
+                command: None,
+                is_preferred: Some(is_preferred),
+                disabled: None,
+                data: None,
+            })
+        };
+
+        // unknown accounts and commodities are declared at the end of the
+        // accounts file, or at the top of the current file. Like the root
+        // journal, the accounts file is relative to the workspace (or else the
+        // root journal), not to whichever file is being edited.
+        let accounts_dir = state
+            .workspace_root
+            .as_deref()
+            .or_else(|| {
+                state
+                    .backend
+                    .root_journal()
+                    .and_then(|root| Path::new(root).parent())
+            })
+            .or_else(|| Path::new(pathname).parent());
+        let accounts_file = state.config.accounts_file.as_ref().map(|accounts_file| {
+            let accounts_file = resolve_path(accounts_file, accounts_dir);
+            Url::from_file_path(&accounts_file)
+                .map_err(|()| accounts_file.to_string_lossy().to_string())
+                .map(|uri| {
+                    let content = state
+                        .backend
+                        .document(uri.path())
+                        .map(str::to_string)
+                        .or_else(|| contents_of_path(uri.path()).ok())
+                        .unwrap_or_default();
+                    (uri, content)
+                })
+        });
+        let accounts_file = match accounts_file {
+            Some(Err(accounts_file)) => {
+                log!(
+                    self,
+                    ERROR,
+                    "[code_action] Unable to build url for {accounts_file}"
+                );
+                None
+            }
+            Some(Ok(accounts_file)) => Some(accounts_file),
+            None => None,
+        };
+        let declare = |directive: String| match &accounts_file {
+            Some((uri, content)) => {
+                let mut edit = append_edit(content, directive);
+                edit.range = Columns::new(content, utf8).client_range(edit.range);
+                (uri.clone(), edit)
+            }
+            None => (
+                params.text_document.uri.clone(),
+                TextEdit::new(Range::default(), directive),
+            ),
+        };
+
+        // quick fixes for unknown accounts
+        for diagnostic in params.context.diagnostics.iter().filter(|diagnostic| {
+            diagnostic.code == Some(NumberOrString::String(UNKNOWN_ACCOUNT.to_string()))
+        }) {
+            let range = columns.byte_range(diagnostic.range);
+            let account = match contents
+                .lines()
+                .nth(range.start.line as usize)
+                .and_then(|line| {
+                    line.get(range.start.character as usize..range.end.character as usize)
+                }) {
+                Some(account) => account.to_string(),
+                None => continue,
+            };
+
+            let mut visited = HashSet::new();
+            match state
+                .backend
+                .similar_accounts(pathname, &contents, &account, &mut visited)
+            {
+                Ok(similar) => {
+                    for (i, similar) in similar.into_iter().enumerate() {
+                        actions.push(make_quick_fix(
+                            format!("Change to `{similar}`"),
+                            diagnostic,
+                            params.text_document.uri.clone(),
+                            TextEdit::new(diagnostic.range, similar),
+                            i == 0,
+                        ));
+                    }
+                }
+                Err(err) => log!(self, ERROR, "[code_action] {err}"),
+            }
+
+            let (uri, edit) = declare(format!("account {account}\n"));
+            actions.push(make_quick_fix(
+                format!("Add `account {account}` directive"),
+                diagnostic,
+                uri,
+                edit,
+                false,
+            ));
+        }
+
+        // quick fixes for unknown commodities, w/ a format matching how the
+        // commodity is used
+        for diagnostic in params.context.diagnostics.iter().filter(|diagnostic| {
+            diagnostic.code == Some(NumberOrString::String(UNKNOWN_COMMODITY.to_string()))
+        }) {
+            let range = columns.byte_range(diagnostic.range);
+            let commodity = match contents
+                .lines()
+                .nth(range.start.line as usize)
+                .and_then(|line| {
+                    line.get(range.start.character as usize..range.end.character as usize)
+                }) {
+                Some(commodity) => commodity.to_string(),
+                None => continue,
+            };
+
+            let mut visited = HashSet::new();
+            let directive =
+                match state
+                    .backend
+                    .commodity_format(pathname, &contents, &commodity, &mut visited)
+                {
+                    Ok(Some(format)) => format!("commodity {commodity}\n    format {format}\n"),
+                    Ok(None) => format!("commodity {commodity}\n"),
+                    Err(err) => {
+                        log!(self, ERROR, "[code_action] {err}");
+                        format!("commodity {commodity}\n")
+                    }
+                };
+
+            let (uri, edit) = declare(directive);
+            actions.push(make_quick_fix(
+                format!("Add `commodity {commodity}` directive"),
+                diagnostic,
+                uri,
+                edit,
+                true,
+            ));
+        }
+
+        // the quick fixes read the journal's included files into the cache
+        state.backend.evict();
+
+        log!(self, "[code_action:response] @ {:?}", start_time.elapsed());
         Ok(Some(actions))
     }
 
@@ -440,19 +1431,25 @@ impl LanguageServer for Lsp {
             start_time.elapsed()
         );
         let pathname = params.text_document_position.text_document.uri.path();
-        let contents = match state.sources.get(pathname) {
-            Some(contents) => contents.clone(),
+        let contents = match state.backend.document(pathname) {
+            Some(contents) => contents.to_string(),
             None => return Ok(None),
         };
 
+        let columns = Columns::new(&contents, state.utf8_positions);
         let mut visited = HashSet::new();
-        let (range, completions) = match state.backend.completions_for_position(
+        let result = state.backend.completions_for_position(
             pathname,
             &contents,
-            &params.text_document_position.position,
+            &columns.byte_position(params.text_document_position.position),
             &mut visited,
-        ) {
-            Ok(CompletionResult::Some { range, completions }) => (range, completions),
+        );
+        // included files were read into the cache
+        state.backend.evict();
+        let (range, completions) = match result {
+            Ok(CompletionResult::Some { range, completions }) => {
+                (columns.client_range(range), completions)
+            }
             Ok(CompletionResult::None) => {
                 log_debug!(
                     self,
@@ -536,7 +1533,7 @@ impl LanguageServer for Lsp {
             return Ok(None);
         }
 
-        let source = match state.sources.get(params.text_document.uri.path()) {
+        let source = match state.backend.document(params.text_document.uri.path()) {
             Some(source) => source,
             None => return Ok(None),
         };
@@ -545,7 +1542,7 @@ impl LanguageServer for Lsp {
             Ok(formatted) => formatted,
             Err(err) => {
                 log!(self, ERROR, "{err}");
-                source.clone()
+                source.to_string()
             }
         };
 
@@ -563,81 +1560,504 @@ impl LanguageServer for Lsp {
         }]))
     }
 
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        log_debug!(self, "[hover] {params:?}");
+
+        let mut state = self.state.lock().await;
+        let pathname = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .path();
+        let contents = match state.backend.document(pathname) {
+            Some(contents) => contents.to_string(),
+            None => return Ok(None),
+        };
+
+        let columns = Columns::new(&contents, state.utf8_positions);
+        let mut visited = HashSet::new();
+        let balance = state.backend.account_balance_at_position(
+            pathname,
+            &contents,
+            &columns.byte_position(params.text_document_position_params.position),
+            &mut visited,
+        );
+        state.backend.evict();
+        let balance = match balance {
+            Ok(Some(balance)) => balance,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                log!(self, ERROR, "[hover] {err}");
+                return Ok(None);
+            }
+        };
+
+        let mut value = format!("**{}**\n\n", balance.account);
+        if let Some(as_of_posting) = balance.as_of_posting {
+            value.push_str(&format!(
+                "Balance after this posting: `{as_of_posting}`\n\n"
+            ));
+        }
+        value.push_str(&format!("Total balance: `{}`", balance.total));
+
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(columns.client_range(balance.range)),
+        }))
+    }
+
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        log_debug!(self, "[references] {params:?}");
+
+        let mut state = self.state.lock().await;
+        let pathname = params.text_document_position.text_document.uri.path();
+        let contents = match state.backend.document(pathname) {
+            Some(contents) => contents.to_string(),
+            None => return Ok(None),
+        };
+
+        let position = Columns::new(&contents, state.utf8_positions)
+            .byte_position(params.text_document_position.position);
+        let reference = match state
+            .backend
+            .reference_at_position(pathname, &contents, &position)
+        {
+            Ok(Some((reference, _))) => reference,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                log!(self, ERROR, "[references] {err}");
+                return Ok(None);
+            }
+        };
+
+        let mut visited = HashSet::new();
+        let locations = state
+            .backend
+            .references(pathname, &contents, &reference, &mut visited);
+        // the included files are needed for their columns
+        let locations = locations.map(|locations| {
+            let mut columns = HashMap::new();
+            locations
+                .into_iter()
+                .map(|location| {
+                    let columns = columns
+                        .entry(location.path.clone())
+                        .or_insert_with(|| state.columns(&location.path));
+                    ReferenceLocation {
+                        range: columns.client_range(location.range),
+                        ..location
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+        state.backend.evict();
+        let locations = match locations {
+            Ok(locations) => locations,
+            Err(err) => {
+                log!(self, ERROR, "[references] {err}");
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(
+            locations
+                .into_iter()
+                .filter(|location| params.context.include_declaration || !location.declaration)
+                .filter_map(|location| {
+                    Url::from_file_path(&location.path)
+                        .ok()
+                        .map(|uri| Location::new(uri, location.range))
+                })
+                .collect(),
+        ))
+    }
+
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        log_debug!(self, "[prepare_rename] {params:?}");
+
+        let mut state = self.state.lock().await;
+        let contents = match state.backend.document(params.text_document.uri.path()) {
+            Some(contents) => contents.to_string(),
+            None => return Ok(None),
+        };
+
+        let columns = Columns::new(&contents, state.utf8_positions);
+        match state.backend.reference_at_position(
+            params.text_document.uri.path(),
+            &contents,
+            &columns.byte_position(params.position),
+        ) {
+            Ok(Some((Reference::Account(_), range))) => Ok(Some(PrepareRenameResponse::Range(
+                columns.client_range(range),
+            ))),
+            Ok(Some(_)) | Ok(None) => Ok(None),
+            Err(err) => {
+                log!(self, ERROR, "[prepare_rename] {err}");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        log_debug!(self, "[rename] {params:?}");
+
+        let new_name = params.new_name.trim();
+        // two spaces or a tab would end the account name
+        if new_name.is_empty() || new_name.contains("  ") || new_name.contains('\t') {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                "Invalid account name '{}'",
+                params.new_name
+            )));
+        }
+
+        let mut state = self.state.lock().await;
+        let pathname = params.text_document_position.text_document.uri.path();
+        let contents = match state.backend.document(pathname) {
+            Some(contents) => contents.to_string(),
+            None => return Ok(None),
+        };
+
+        let position = Columns::new(&contents, state.utf8_positions)
+            .byte_position(params.text_document_position.position);
+        let account = match state
+            .backend
+            .reference_at_position(pathname, &contents, &position)
+        {
+            Ok(Some((Reference::Account(account), _))) => account,
+            Ok(Some(_)) | Ok(None) => return Ok(None),
+            Err(err) => {
+                log!(self, ERROR, "[rename] {err}");
+                return Ok(None);
+            }
+        };
+
+        let include_subaccounts = state.config.rename_subaccounts;
+        let mut visited = HashSet::new();
+        let edits = state.backend.rename_account(
+            pathname,
+            &contents,
+            &account,
+            new_name,
+            include_subaccounts,
+            &mut visited,
+        );
+        // the included files are needed for their columns
+        let edits = edits.map(|edits| {
+            edits
+                .into_iter()
+                .map(|(path, edits)| {
+                    let columns = state.columns(&path);
+                    let edits = edits
+                        .into_iter()
+                        .map(|edit| TextEdit::new(columns.client_range(edit.range), edit.new_text))
+                        .collect::<Vec<_>>();
+                    (path, edits)
+                })
+                .collect::<Vec<_>>()
+        });
+        state.backend.evict();
+        let edits = match edits {
+            Ok(edits) => edits,
+            Err(err) => {
+                log!(self, ERROR, "[rename] {err}");
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(WorkspaceEdit {
+            changes: Some(
+                edits
+                    .into_iter()
+                    .filter_map(|(path, edits)| {
+                        Url::from_file_path(&path).ok().map(|uri| (uri, edits))
+                    })
+                    .collect(),
+            ),
+            ..WorkspaceEdit::default()
+        }))
+    }
+
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        log_debug!(self, "[document_symbol] {params:?}");
+
+        let mut state = self.state.lock().await;
+        let contents = match state.backend.document(params.text_document.uri.path()) {
+            Some(contents) => contents.to_string(),
+            None => return Ok(None),
+        };
+
+        let columns = Columns::new(&contents, state.utf8_positions);
+        match state
+            .backend
+            .document_symbols(params.text_document.uri.path(), &contents)
+        {
+            Ok(symbols) => Ok(Some(DocumentSymbolResponse::Nested(
+                symbols
+                    .into_iter()
+                    .map(|symbol| client_symbol(&columns, symbol))
+                    .collect(),
+            ))),
+            Err(err) => {
+                log!(self, ERROR, "[document_symbol] {err}");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        log_debug!(self, "[symbol] {params:?}");
+
+        let mut state = self.state.lock().await;
+        let mut sources: Vec<_> = state.root_document().into_iter().collect();
+        sources.extend(state.backend.open_documents());
+
+        // files may include each other, so only walk each file once
+        let mut symbols = HashMap::new();
+        let mut visited = HashSet::new();
+        for (path, contents) in sources {
+            if visited.contains(&path) {
+                continue;
+            }
+            if let Err(err) = state
+                .backend
+                .symbols(&path, &contents, &mut symbols, &mut visited)
+            {
+                log!(self, ERROR, "[symbol] {err}");
+            }
+        }
+
+        let query = params.query.to_lowercase();
+        let mut columns = HashMap::new();
+        let mut symbols = symbols
+            .into_iter()
+            .filter_map(|(reference, location)| {
+                let (name, kind, container_name) = match reference {
+                    Reference::Account(name) => (name, SymbolKind::NAMESPACE, "account"),
+                    Reference::Commodity(name) => (name, SymbolKind::CONSTANT, "commodity"),
+                    Reference::Payee(name) => (name, SymbolKind::OBJECT, "payee"),
+                    Reference::Tag(name) => (name, SymbolKind::PROPERTY, "tag"),
+                };
+                if !name.to_lowercase().contains(&query) {
+                    return None;
+                }
+
+                #[allow(deprecated)]
+                Url::from_file_path(&location.path)
+                    .ok()
+                    .map(|uri| SymbolInformation {
+                        name,
+                        kind,
+                        tags: None,
+                        deprecated: None,
+                        location: Location::new(
+                            uri,
+                            columns
+                                .entry(location.path.clone())
+                                .or_insert_with(|| state.columns(&location.path))
+                                .client_range(location.range),
+                        ),
+                        container_name: Some(container_name.to_string()),
+                    })
+            })
+            .collect::<Vec<_>>();
+        // the included files were needed for their columns
+        drop(columns);
+        state.backend.evict();
+        symbols.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Some(symbols))
+    }
+
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        log_debug!(self, "[folding_range] {params:?}");
+
+        let state = self.state.lock().await;
+        let contents = match state.backend.document(params.text_document.uri.path()) {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+
+        match state
+            .backend
+            .folding_ranges(params.text_document.uri.path(), contents)
+        {
+            Ok(ranges) => Ok(Some(ranges)),
+            Err(err) => {
+                log!(self, ERROR, "[folding_range] {err}");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn semantic_tokens_full(
+        &self,
+        params: SemanticTokensParams,
+    ) -> Result<Option<SemanticTokensResult>> {
+        log_debug!(self, "[semantic_tokens_full] {params:?}");
+
+        let state = self.state.lock().await;
+        let contents = match state.backend.document(params.text_document.uri.path()) {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+
+        match state.backend.semantic_tokens(
+            params.text_document.uri.path(),
+            contents,
+            None,
+            state.utf8_positions,
+        ) {
+            Ok(data) => Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
+                result_id: None,
+                data,
+            }))),
+            Err(err) => {
+                log!(self, ERROR, "[semantic_tokens_full] {err}");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn semantic_tokens_range(
+        &self,
+        params: SemanticTokensRangeParams,
+    ) -> Result<Option<SemanticTokensRangeResult>> {
+        log_debug!(self, "[semantic_tokens_range] {params:?}");
+
+        let state = self.state.lock().await;
+        let contents = match state.backend.document(params.text_document.uri.path()) {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+
+        match state.backend.semantic_tokens(
+            params.text_document.uri.path(),
+            contents,
+            Some(params.range),
+            state.utf8_positions,
+        ) {
+            Ok(data) => Ok(Some(SemanticTokensRangeResult::Tokens(SemanticTokens {
+                result_id: None,
+                data,
+            }))),
+            Err(err) => {
+                log!(self, ERROR, "[semantic_tokens_range] {err}");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        log_debug!(self, "[inlay_hint] {params:?}");
+
+        let mut state = self.state.lock().await;
+        let pathname = params.text_document.uri.path();
+        let contents = match state.backend.document(pathname) {
+            Some(contents) => contents.to_string(),
+            None => return Ok(None),
+        };
+
+        let running_balance_accounts = state.config.running_balance_accounts.clone();
+        let mut visited = HashSet::new();
+        let hints = state.backend.inlay_hints(
+            pathname,
+            &contents,
+            &params.range,
+            &running_balance_accounts,
+            &mut visited,
+        );
+        state.backend.evict();
+        let columns = Columns::new(&contents, state.utf8_positions);
+        match hints {
+            Ok(hints) => Ok(Some(
+                hints
+                    .into_iter()
+                    .map(|hint| InlayHint {
+                        position: columns.client_position(hint.position),
+                        ..hint
+                    })
+                    .collect(),
+            )),
+            Err(err) => {
+                log!(self, ERROR, "[inlay_hint] {err}");
+                Ok(None)
+            }
+        }
+    }
+
     async fn goto_definition(
         &self,
         params: GotoDefinitionParams,
     ) -> Result<Option<GotoDefinitionResponse>> {
         log_debug!(self, "[goto_definition] {params:?}");
 
-        let state = self.state.lock().await;
+        let mut state = self.state.lock().await;
         let buffer_path = params
             .text_document_position_params
             .text_document
             .uri
             .path();
-        let source = match state.sources.get(buffer_path) {
-            Some(source) => source,
+        let source = match state.backend.document(buffer_path) {
+            Some(source) => source.to_string(),
             None => return Ok(None),
         };
 
-        let line_num = params.text_document_position_params.position.line as usize;
-        let line = source.split('\n').nth(line_num).unwrap_or("");
-
-        let path = match line.split_once(' ') {
-            Some(("include", maybe_path)) => {
-                let quotes: &[_] = &['"', '\''];
-                maybe_path.trim().trim_matches(quotes)
-            }
-            None | Some((_, _)) => return Ok(None),
+        let include = match state.backend.include_at(
+            buffer_path,
+            &source,
+            params.text_document_position_params.position.line,
+        ) {
+            Some(include) => include,
+            None => return Ok(None),
         };
+        let origin_selection_range = Some(
+            Columns::new(&source, state.utf8_positions).client_range(Range {
+                start: Position {
+                    line: include.range.start_point.row as u32,
+                    character: include.range.start_point.column as u32,
+                },
+                end: Position {
+                    line: include.range.end_point.row as u32,
+                    character: include.range.end_point.column as u32,
+                },
+            }),
+        );
 
-        let path_start_offset = line.find(path).unwrap_or(0) as u32;
-        let path_len = path.len() as u32;
-        let origin_selection_range = Some(Range {
-            start: Position {
-                line: params.text_document_position_params.position.line,
-                character: path_start_offset,
-            },
-            end: Position {
-                line: params.text_document_position_params.position.line,
-                character: path_start_offset + path_len,
-            },
-        });
-
-        let url = {
-            let path = Path::new(path);
-            let path = if path.is_absolute() {
-                path.to_path_buf()
-            } else {
-                let dir = match Path::new(buffer_path).parent() {
-                    Some(dir) => dir,
-                    None => {
-                        log!(
-                            self,
-                            ERROR,
-                            "[goto_definition] Buffer has no parent dir? {buffer_path}"
-                        );
-                        return Ok(None);
-                    }
-                };
-                let path = dir.join(path);
-                match path.canonicalize() {
-                    Ok(path) => path,
-                    Err(err) => {
-                        log!(
-                            self,
-                            ERROR,
-                            "[goto_definition] Counld not canonicalize {}",
-                            path.to_string_lossy()
-                        );
-                        log!(self, ERROR, "[goto_definition] {err}");
-                        return Ok(None);
-                    }
+        // a glob or directory goes to every file it includes
+        let mut links = Vec::new();
+        for path in include.paths {
+            let path = match Path::new(&path).canonicalize() {
+                Ok(path) => path,
+                Err(err) => {
+                    log!(
+                        self,
+                        ERROR,
+                        "[goto_definition] Counld not canonicalize {path}"
+                    );
+                    log!(self, ERROR, "[goto_definition] {err}");
+                    continue;
                 }
             };
 
             match Url::from_file_path(&path) {
-                Ok(url) => url,
+                Ok(target_uri) => links.push(LocationLink {
+                    origin_selection_range,
+                    target_uri,
+                    target_range: Range::default(),
+                    target_selection_range: Range::default(),
+                }),
                 Err(()) => {
                     log!(
                         self,
@@ -645,17 +2065,15 @@ impl LanguageServer for Lsp {
                         "[goto_definition] Unable to build url for {}",
                         path.to_string_lossy()
                     );
-                    return Ok(None);
                 }
             }
-        };
+        }
+
+        if links.is_empty() {
+            return Ok(None);
+        }
 
-        Ok(Some(GotoDefinitionResponse::Link(vec![LocationLink {
-            origin_selection_range,
-            target_uri: url,
-            target_range: Range::default(),
-            target_selection_range: Range::default(),
-        }])))
+        Ok(Some(GotoDefinitionResponse::Link(links)))
     }
 }
 
@@ -735,16 +2153,137 @@ mod test {
             "#
         );
 
-        insta::assert_debug_snapshot!(response.capabilities.text_document_sync,
+        insta::assert_debug_snapshot!(response.capabilities.diagnostic_provider,
             @r#"
             Some(
-                Kind(
-                    Full,
-                ),
-            )
+                Options(
+                    DiagnosticOptions {
+                        identifier: Some(
+                            "ledger",
+                        ),
+                        inter_file_dependencies: true,
+                        workspace_diagnostics: true,
+                        work_done_progress_options: WorkDoneProgressOptions {
+                            work_done_progress: None,
+                        },
+                    },
+                ),
+            )
+            "#
+        );
+
+        insta::assert_debug_snapshot!(response.capabilities.document_symbol_provider,
+            @r#"
+            Some(
+                Left(
+                    true,
+                ),
+            )
+            "#
+        );
+
+        insta::assert_debug_snapshot!(response.capabilities.folding_range_provider,
+            @r#"
+            Some(
+                Simple(
+                    true,
+                ),
+            )
+            "#
+        );
+
+        insta::assert_debug_snapshot!(response.capabilities.hover_provider,
+            @r#"
+            Some(
+                Simple(
+                    true,
+                ),
+            )
+            "#
+        );
+
+        insta::assert_debug_snapshot!(response.capabilities.references_provider,
+            @r#"
+            Some(
+                Left(
+                    true,
+                ),
+            )
+            "#
+        );
+
+        insta::assert_debug_snapshot!(response.capabilities.rename_provider,
+            @r#"
+            Some(
+                Right(
+                    RenameOptions {
+                        prepare_provider: Some(
+                            true,
+                        ),
+                        work_done_progress_options: WorkDoneProgressOptions {
+                            work_done_progress: None,
+                        },
+                    },
+                ),
+            )
+            "#
+        );
+
+        insta::assert_debug_snapshot!(
+            match response.capabilities.semantic_tokens_provider {
+                Some(SemanticTokensServerCapabilities::SemanticTokensOptions(options)) => {
+                    Some((options.range, options.full))
+                }
+                Some(_) | None => None,
+            },
+            @r#"
+            Some(
+                (
+                    Some(
+                        true,
+                    ),
+                    Some(
+                        Bool(
+                            true,
+                        ),
+                    ),
+                ),
+            )
             "#
         );
 
+        insta::assert_debug_snapshot!(response.capabilities.inlay_hint_provider,
+            @r#"
+            Some(
+                Left(
+                    true,
+                ),
+            )
+            "#
+        );
+
+        insta::assert_debug_snapshot!(response.capabilities.text_document_sync,
+            @r#"
+            Some(
+                Kind(
+                    Incremental,
+                ),
+            )
+            "#
+        );
+
+        insta::assert_debug_snapshot!(response.capabilities.workspace_symbol_provider,
+            @r#"
+            Some(
+                Left(
+                    true,
+                ),
+            )
+            "#
+        );
+
+        insta::assert_debug_snapshot!(response.capabilities.position_encoding, @"None");
+
         insta::assert_debug_snapshot!(response.capabilities.workspace,
             @r#"
             Some(
@@ -791,6 +2330,80 @@ mod test {
         Ok(())
     }
 
+    #[test_log::test(tokio::test)]
+    async fn initialize_with_position_encodings() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+
+        let request = jsonrpc::Request::build("initialize")
+            .id(1)
+            .params(serde_json::json!({
+                "capabilities":{ "general": { "positionEncodings": ["utf-16", "utf-8"] } },
+            }))
+            .finish();
+
+        let response = context
+            .request::<lsp_types::InitializeResult>(&request)
+            .await?;
+
+        insta::assert_debug_snapshot!(response.capabilities.position_encoding, @r#"
+        Some(
+            PositionEncodingKind(
+                "utf-8",
+            ),
+        )
+        "#);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn incremental_changes_with_utf16_positions() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+        // no `positionEncodings`, so columns are UTF-16 code units
+        context.initialize().await?;
+
+        context
+            .prep_document("2024/01/01 Café\n    Expenses:Food\n")
+            .await?;
+
+        // after "é", which is 1 code unit but 2 bytes
+        context
+            .change_document(
+                1,
+                serde_json::json!([{
+                    "range": {
+                        "start": { "line": 0, "character": 15 },
+                        "end":   { "line": 0, "character": 15 }
+                    },
+                    "text": " & Co"
+                }]),
+            )
+            .await?;
+
+        let request = jsonrpc::Request::build("textDocument/formatting")
+            .id(2)
+            .params(serde_json::json!({
+                "textDocument": { "uri": "file:///foo.ledger" },
+                "options": { "tabSize": 4, "insertSpaces": true }
+            }))
+            .finish();
+        let edits = context
+            .request::<Option<Vec<TextEdit>>>(&request)
+            .await?
+            .unwrap_or_default();
+        let formatted = edits
+            .iter()
+            .map(|edit| edit.new_text.trim())
+            .collect::<Vec<_>>()
+            .join("\n");
+        insta::assert_snapshot!(formatted, @r"
+        2024/01/01 Café & Co
+            Expenses:Food
+        ");
+
+        Ok(())
+    }
+
     #[test_log::test(tokio::test)]
     async fn code_actions_with_pending_xacts() -> anyhow::Result<()> {
         let mut context = TestContext::new().await?;
@@ -918,247 +2531,1026 @@ mod test {
 
         let actions = context.code_action(5, 10).await?.unwrap();
 
-        let actions = actions
-            .iter()
-            .map(|a: &CodeActionOrCommand| match a {
-                CodeActionOrCommand::Command(_) => todo!(),
-                CodeActionOrCommand::CodeAction(action) => (
-                    &action.title,
-                    action
-                        .edit
-                        .as_ref()
-                        .unwrap()
-                        .changes
-                        .as_ref()
-                        .unwrap()
-                        .values()
-                        .collect::<Vec<_>>(),
-                ),
-            })
-            .collect::<Vec<_>>();
-        insta::assert_debug_snapshot!(actions,
-            @r#"
+        let actions = actions
+            .iter()
+            .map(|a: &CodeActionOrCommand| match a {
+                CodeActionOrCommand::Command(_) => todo!(),
+                CodeActionOrCommand::CodeAction(action) => (
+                    &action.title,
+                    action
+                        .edit
+                        .as_ref()
+                        .unwrap()
+                        .changes
+                        .as_ref()
+                        .unwrap()
+                        .values()
+                        .collect::<Vec<_>>(),
+                ),
+            })
+            .collect::<Vec<_>>();
+        insta::assert_debug_snapshot!(actions,
+            @r#"
+            [
+                (
+                    "Mark transaction as pending (!)",
+                    [
+                        [
+                            TextEdit {
+                                range: Range {
+                                    start: Position {
+                                        line: 4,
+                                        character: 8,
+                                    },
+                                    end: Position {
+                                        line: 4,
+                                        character: 10,
+                                    },
+                                },
+                                new_text: " !",
+                            },
+                        ],
+                    ],
+                ),
+                (
+                    "Mark transaction as not cleared",
+                    [
+                        [
+                            TextEdit {
+                                range: Range {
+                                    start: Position {
+                                        line: 4,
+                                        character: 8,
+                                    },
+                                    end: Position {
+                                        line: 4,
+                                        character: 10,
+                                    },
+                                },
+                                new_text: "",
+                            },
+                        ],
+                    ],
+                ),
+            ]
+            "#
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn code_actions_with_effective_date() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+        context.initialize().await?;
+
+        let source = textwrap::dedent(
+            "
+            2024/01/02=2025/03/04 Payee1
+                Account
+                Account
+            ",
+        );
+        context.prep_document(&source).await?;
+
+        let actions = context.code_action(2, 10).await?.unwrap();
+
+        let actions = actions
+            .iter()
+            .map(|a: &CodeActionOrCommand| match a {
+                CodeActionOrCommand::Command(_) => todo!(),
+                CodeActionOrCommand::CodeAction(action) => (
+                    &action.title,
+                    action
+                        .edit
+                        .as_ref()
+                        .unwrap()
+                        .changes
+                        .as_ref()
+                        .unwrap()
+                        .values()
+                        .collect::<Vec<_>>(),
+                ),
+            })
+            .collect::<Vec<_>>();
+        insta::assert_debug_snapshot!(actions,
+            @r#"
+                [
+                    (
+                        "Mark transaction as pending (!)",
+                        [
+                            [
+                                TextEdit {
+                                    range: Range {
+                                        start: Position {
+                                            line: 1,
+                                            character: 21,
+                                        },
+                                        end: Position {
+                                            line: 1,
+                                            character: 21,
+                                        },
+                                    },
+                                    new_text: " !",
+                                },
+                            ],
+                        ],
+                    ),
+                    (
+                        "Mark transaction as cleared (*)",
+                        [
+                            [
+                                TextEdit {
+                                    range: Range {
+                                        start: Position {
+                                            line: 1,
+                                            character: 21,
+                                        },
+                                        end: Position {
+                                            line: 1,
+                                            character: 21,
+                                        },
+                                    },
+                                    new_text: " *",
+                                },
+                            ],
+                        ],
+                    ),
+                ]
+                "#
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn code_actions_bug_with_maybe_invalid_xacts() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+        context.initialize().await?;
+
+        let source = vec![
+            textwrap::dedent(
+                "
+                24/01/02 Payee1
+                    Account",
+            ),
+            // a line w/ 4 spaces, like we just hit <enter> to add another account
+            "    ".to_string(),
+            // actual blank line between above and below xacts
+            textwrap::dedent(
+                "
+                24/01/03 Payee2
+                    Account2
+                ",
+            ),
+        ]
+        .join("\n");
+        context.prep_document(&source).await?;
+        // ask for actions at pos 3:4 (end of new "empty" account line)
+        let actions = context.code_action(3, 4).await?.unwrap();
+
+        let actions = actions
+            .iter()
+            .map(|a: &CodeActionOrCommand| match a {
+                CodeActionOrCommand::Command(_) => todo!(),
+                CodeActionOrCommand::CodeAction(action) => (
+                    &action.title,
+                    action
+                        .edit
+                        .as_ref()
+                        .unwrap()
+                        .changes
+                        .as_ref()
+                        .unwrap()
+                        .values()
+                        .collect::<Vec<_>>(),
+                ),
+            })
+            .collect::<Vec<_>>();
+        insta::assert_debug_snapshot!(actions,
+            @r#"
+            [
+                (
+                    "Mark transaction as pending (!)",
+                    [
+                        [
+                            TextEdit {
+                                range: Range {
+                                    start: Position {
+                                        line: 1,
+                                        character: 8,
+                                    },
+                                    end: Position {
+                                        line: 1,
+                                        character: 8,
+                                    },
+                                },
+                                new_text: " !",
+                            },
+                        ],
+                    ],
+                ),
+                (
+                    "Mark transaction as cleared (*)",
+                    [
+                        [
+                            TextEdit {
+                                range: Range {
+                                    start: Position {
+                                        line: 1,
+                                        character: 8,
+                                    },
+                                    end: Position {
+                                        line: 1,
+                                        character: 8,
+                                    },
+                                },
+                                new_text: " *",
+                            },
+                        ],
+                    ],
+                ),
+            ]
+            "#
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn code_actions_for_unknown_account() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+        context.initialize().await?;
+
+        let source = textwrap::dedent(
+            "
+            account Expenses:Groceries
+
+            24/01/02 Grocer
+                Expenses:Groceires    $10
+                Assets
+            ",
+        );
+        context.prep_document(&source).await?;
+
+        let diagnostics = serde_json::json!([{
+            "range": {
+                "start": { "line": 4, "character": 4 },
+                "end":   { "line": 4, "character": 22 }
+            },
+            "severity": 2,
+            "code": "unknown-account",
+            "message": "Unknown account 'Expenses:Groceires'"
+        }]);
+        let actions = context
+            .code_action_with_diagnostics(4, 10, diagnostics)
+            .await?
+            .unwrap_or_default();
+
+        let actions = actions
+            .iter()
+            .filter_map(|a: &CodeActionOrCommand| match a {
+                CodeActionOrCommand::CodeAction(action)
+                    if action.kind == Some(CodeActionKind::QUICKFIX) =>
+                {
+                    Some((
+                        &action.title,
+                        action
+                            .edit
+                            .as_ref()
+                            .and_then(|edit| edit.changes.as_ref())
+                            .map(|changes| changes.values().collect::<Vec<_>>()),
+                    ))
+                }
+                CodeActionOrCommand::CodeAction(_) | CodeActionOrCommand::Command(_) => None,
+            })
+            .collect::<Vec<_>>();
+        insta::assert_debug_snapshot!(actions,
+            @r#"
+            [
+                (
+                    "Change to `Expenses:Groceries`",
+                    Some(
+                        [
+                            [
+                                TextEdit {
+                                    range: Range {
+                                        start: Position {
+                                            line: 4,
+                                            character: 4,
+                                        },
+                                        end: Position {
+                                            line: 4,
+                                            character: 22,
+                                        },
+                                    },
+                                    new_text: "Expenses:Groceries",
+                                },
+                            ],
+                        ],
+                    ),
+                ),
+                (
+                    "Add `account Expenses:Groceires` directive",
+                    Some(
+                        [
+                            [
+                                TextEdit {
+                                    range: Range {
+                                        start: Position {
+                                            line: 0,
+                                            character: 0,
+                                        },
+                                        end: Position {
+                                            line: 0,
+                                            character: 0,
+                                        },
+                                    },
+                                    new_text: "account Expenses:Groceires\n",
+                                },
+                            ],
+                        ],
+                    ),
+                ),
+            ]
+            "#
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn code_actions_for_unknown_commodity() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+        context.initialize().await?;
+
+        let source = textwrap::dedent(
+            "
+            24/01/02 Exchange
+                Assets:Cash    1,250.5 EUR @ $1.10
+                Assets:Cash    -25.25 EUR
+            ",
+        );
+        context.prep_document(&source).await?;
+
+        let diagnostics = serde_json::json!([{
+            "range": {
+                "start": { "line": 2, "character": 27 },
+                "end":   { "line": 2, "character": 30 }
+            },
+            "severity": 2,
+            "code": "unknown-commodity",
+            "message": "Unknown commodity 'EUR'"
+        }]);
+        let actions = context
+            .code_action_with_diagnostics(2, 28, diagnostics)
+            .await?
+            .unwrap_or_default();
+
+        let actions = actions
+            .iter()
+            .filter_map(|a: &CodeActionOrCommand| match a {
+                CodeActionOrCommand::CodeAction(action)
+                    if action.kind == Some(CodeActionKind::QUICKFIX) =>
+                {
+                    Some((
+                        &action.title,
+                        action
+                            .edit
+                            .as_ref()
+                            .and_then(|edit| edit.changes.as_ref())
+                            .map(|changes| {
+                                changes
+                                    .values()
+                                    .flatten()
+                                    .map(|edit| &edit.new_text)
+                                    .collect::<Vec<_>>()
+                            }),
+                    ))
+                }
+                CodeActionOrCommand::CodeAction(_) | CodeActionOrCommand::Command(_) => None,
+            })
+            .collect::<Vec<_>>();
+        insta::assert_debug_snapshot!(actions,
+            @r#"
+            [
+                (
+                    "Add `commodity EUR` directive",
+                    Some(
+                        [
+                            "commodity EUR\n    format 1,000.00 EUR\n",
+                        ],
+                    ),
+                ),
+            ]
+            "#
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn code_actions_for_unknown_commodity_in_subdirectory() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+        let request = jsonrpc::Request::build("initialize")
+            .id(1)
+            .params(serde_json::json!({
+                "capabilities":{},
+                "rootUri": "file:///ledger",
+                "initializationOptions":{ "accounts_file": "accounts.ledger" }
+            }))
+            .finish();
+        let _ = context
+            .request::<lsp_types::InitializeResult>(&request)
+            .await?;
+
+        let source = textwrap::dedent(
+            "
+            24/01/02 Exchange
+                Assets:Cash    1,250.5 EUR @ $1.10
+                Assets:Cash    -25.25 EUR
+            ",
+        );
+        context
+            .open_document("file:///ledger/2024/foo.ledger", &source)
+            .await?;
+
+        // the accounts file is relative to the workspace, not to the buffer
+        let request = jsonrpc::Request::build("textDocument/codeAction")
+            .id(3)
+            .params(serde_json::json!({
+                "range":{
+                    "start": { "line": 2, "character": 28 },
+                    "end":   { "line": 2, "character": 28 }
+                },
+                "context": { "diagnostics": [{
+                    "range": {
+                        "start": { "line": 2, "character": 27 },
+                        "end":   { "line": 2, "character": 30 }
+                    },
+                    "severity": 2,
+                    "code": "unknown-commodity",
+                    "message": "Unknown commodity 'EUR'"
+                }] },
+                "textDocument":{ "uri": "file:///ledger/2024/foo.ledger" }
+            }))
+            .finish();
+        let actions = context
+            .request::<Option<CodeActionResponse>>(&request)
+            .await?
+            .unwrap_or_default();
+
+        let changes = actions
+            .iter()
+            .filter_map(|a: &CodeActionOrCommand| match a {
+                CodeActionOrCommand::CodeAction(action)
+                    if action.kind == Some(CodeActionKind::QUICKFIX) =>
+                {
+                    action.edit.as_ref().and_then(|edit| edit.changes.as_ref())
+                }
+                CodeActionOrCommand::CodeAction(_) | CodeActionOrCommand::Command(_) => None,
+            })
+            .flatten()
+            .map(|(uri, edits)| {
+                (
+                    uri.as_str(),
+                    edits.iter().map(|edit| &edit.new_text).collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        insta::assert_debug_snapshot!(changes,
+            @r#"
+            [
+                (
+                    "file:///ledger/accounts.ledger",
+                    [
+                        "commodity EUR\n    format 1,000.00 EUR\n",
+                    ],
+                ),
+            ]
+            "#
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn diagnostics_on_change() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+        context.initialize().await?;
+
+        let source = textwrap::dedent(
+            "
+            2024/01/01 Valid
+                Expenses:Food    $10.00
+                Assets:Checking
+            ",
+        );
+        context.prep_document(&source).await?;
+
+        let opened = context.recv::<PublishDiagnosticsParams>().await?;
+        insta::assert_debug_snapshot!((opened.version, opened.diagnostics.len()), @r"
+        (
+            Some(
+                0,
+            ),
+            0,
+        )
+        ");
+
+        context
+            .change_document(
+                1,
+                serde_json::json!([{
+                    "range": {
+                        "start": { "line": 4, "character": 0 },
+                        "end":   { "line": 4, "character": 0 }
+                    },
+                    "text": "\n&&& ???\n"
+                }]),
+            )
+            .await?;
+
+        let changed = context.recv::<PublishDiagnosticsParams>().await?;
+        let mut rows = changed
+            .diagnostics
+            .iter()
+            .map(|d| d.range.start.line)
+            .collect::<Vec<_>>();
+        rows.dedup();
+        insta::assert_debug_snapshot!((changed.version, rows), @r"
+        (
+            Some(
+                1,
+            ),
+            [
+                5,
+            ],
+        )
+        ");
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn diagnostics_on_change_cancels_older_versions() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+
+        let request = jsonrpc::Request::build("initialize")
+            .id(1)
+            .params(serde_json::json!({
+                "capabilities":{},
+                "initializationOptions":{ "diagnostics_delay": 100 }
+            }))
+            .finish();
+        let _ = context
+            .request::<lsp_types::InitializeResult>(&request)
+            .await?;
+
+        context.prep_document("").await?;
+        let _ = context.recv::<PublishDiagnosticsParams>().await?;
+
+        for version in [1, 2] {
+            context
+                .change_document(
+                    version,
+                    serde_json::json!([{
+                        "range": {
+                            "start": { "line": 0, "character": 0 },
+                            "end":   { "line": 0, "character": 0 }
+                        },
+                        "text": "&&& ???\n"
+                    }]),
+                )
+                .await?;
+        }
+
+        let changed = context.recv::<PublishDiagnosticsParams>().await?;
+        insta::assert_debug_snapshot!((changed.version, changed.diagnostics.is_empty()), @r"
+        (
+            Some(
+                2,
+            ),
+            false,
+        )
+        ");
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn diagnostics_on_save_cancels_pending_diagnostics() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+
+        let request = jsonrpc::Request::build("initialize")
+            .id(1)
+            .params(serde_json::json!({
+                "capabilities":{},
+                "initializationOptions":{ "diagnostics_delay": 100 }
+            }))
+            .finish();
+        let _ = context
+            .request::<lsp_types::InitializeResult>(&request)
+            .await?;
+
+        context.prep_document("").await?;
+        let _ = context.recv::<PublishDiagnosticsParams>().await?;
+
+        context
+            .change_document(
+                1,
+                serde_json::json!([{
+                    "range": {
+                        "start": { "line": 0, "character": 0 },
+                        "end":   { "line": 0, "character": 0 }
+                    },
+                    "text": "&&& ???\n"
+                }]),
+            )
+            .await?;
+        let request = jsonrpc::Request::build("textDocument/didSave")
+            .params(serde_json::json!({"textDocument":{ "uri": "file:///foo.ledger" }}))
+            .finish();
+        context.send(&request).await?;
+
+        let saved = context.recv::<PublishDiagnosticsParams>().await?;
+        // the diagnostics pending since the change aren't published again
+        let pending = tokio::time::timeout(
+            Duration::from_millis(300),
+            context.recv::<PublishDiagnosticsParams>(),
+        )
+        .await;
+        insta::assert_debug_snapshot!((saved.version, pending.is_err()), @r"
+        (
+            Some(
+                1,
+            ),
+            true,
+        )
+        ");
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn diagnostics_for_included_files() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+        context.initialize().await?;
+
+        let included = textwrap::dedent(
+            "
+            2024/01/03 Unbalanced
+                Expenses:Food    $10.00
+                Assets:Checking  $-9.50
+            ",
+        );
+        context
+            .open_document("file:///included.ledger", &included)
+            .await?;
+        let _ = context.recv::<PublishDiagnosticsParams>().await?;
+
+        // the included file is only open in the client, not on disk, which
+        // is fine
+        context
+            .open_document("file:///foo.ledger", "include included.ledger\n")
+            .await?;
+        let mut published = Vec::new();
+        for _ in 0..2 {
+            let params = context.recv::<PublishDiagnosticsParams>().await?;
+            published.push((
+                params.uri.path().to_string(),
+                params
+                    .diagnostics
+                    .into_iter()
+                    .map(|d| (d.range.start.line, d.message))
+                    .collect::<Vec<_>>(),
+            ));
+        }
+        insta::assert_debug_snapshot!(published, @r#"
+        [
+            (
+                "/foo.ledger",
+                [],
+            ),
+            (
+                "/included.ledger",
+                [
+                    (
+                        1,
+                        "Transaction does not balance; unbalanced remainder is $0.50",
+                    ),
+                ],
+            ),
+        ]
+        "#);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn completions_from_root_journal() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join("ledger-lsp-test-root-journal");
+        fs::create_dir_all(&dir)?;
+        fs::write(
+            dir.join("main.ledger"),
+            "include accounts.ledger\ninclude /foo.ledger\n",
+        )?;
+        fs::write(dir.join("accounts.ledger"), "account Assets:Savings\n")?;
+
+        let mut context = TestContext::new().await?;
+        let request = jsonrpc::Request::build("initialize")
+            .id(1)
+            .params(serde_json::json!({
+                "capabilities":{},
+                "initializationOptions":{ "root_journal": dir.join("main.ledger") }
+            }))
+            .finish();
+        let _ = context
+            .request::<lsp_types::InitializeResult>(&request)
+            .await?;
+
+        // foo.ledger doesn't include accounts.ledger, but the root journal
+        // includes both
+        let source = textwrap::dedent(
+            "
+            2024/01/01 Payee
+                Assets:Checking  $1
+                Assets:C
+            ",
+        );
+        context.prep_document(&source).await?;
+        for _ in 0..3 {
+            let _ = context.recv::<PublishDiagnosticsParams>().await?;
+        }
+
+        let completions = match context.completion(3, 12).await? {
+            Some(CompletionResponse::Array(completions)) => completions,
+            Some(CompletionResponse::List(_)) | None => unreachable!(),
+        };
+        let mut labels = completions
+            .into_iter()
+            .map(|item| item.label)
+            .collect::<Vec<_>>();
+        labels.sort();
+        insta::assert_debug_snapshot!(labels, @r#"
+        [
+            "Assets:Checking",
+            "Assets:Savings",
+        ]
+        "#);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_ledgerrc_file() {
+        let ledgerrc = textwrap::dedent(
+            "
+            # comment
+            --filename foo.ledger
+            --file ~/ledger/main.ledger
+            --file=other.ledger
+            ",
+        );
+        insta::assert_debug_snapshot!(
+            [
+                ledgerrc_file(&ledgerrc),
+                ledgerrc_file("--sort date\n--file=other.ledger"),
+                ledgerrc_file("-f  main.ledger "),
+                ledgerrc_file("--sort date"),
+            ],
+            @r#"
+        [
+            Some(
+                "~/ledger/main.ledger",
+            ),
+            Some(
+                "other.ledger",
+            ),
+            Some(
+                "main.ledger",
+            ),
+            None,
+        ]
+        "#
+        );
+    }
+
+    #[test]
+    fn test_append_edit() {
+        let edit = |content: &str| {
+            let edit = append_edit(content, "account Assets\n".to_string());
+            (
+                edit.range.start.line,
+                edit.range.start.character,
+                edit.new_text,
+            )
+        };
+        insta::assert_debug_snapshot!(
+            [edit(""), edit("account Expenses\n"), edit("account Expenses")],
+            @r#"
+        [
+            (
+                0,
+                0,
+                "account Assets\n",
+            ),
+            (
+                1,
+                0,
+                "account Assets\n",
+            ),
+            (
+                0,
+                16,
+                "\naccount Assets\n",
+            ),
+        ]
+        "#
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn completions_from_root_journal_after_configuration_change() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join("ledger-lsp-test-root-journal-configuration");
+        fs::create_dir_all(&dir)?;
+        fs::write(
+            dir.join("main.ledger"),
+            "include accounts.ledger\ninclude /foo.ledger\n",
+        )?;
+        fs::write(dir.join("accounts.ledger"), "account Assets:Savings\n")?;
+
+        let mut context = TestContext::new().await?;
+        context.initialize().await?;
+        context
+            .prep_document("2024/01/01 Payee\n    Assets:Checking  $1\n    Assets:C\n")
+            .await?;
+        let _ = context.recv::<PublishDiagnosticsParams>().await?;
+
+        context
+            .send(
+                &jsonrpc::Request::build("workspace/didChangeConfiguration")
+                    .params(serde_json::json!({
+                        "settings": { "rootJournal": dir.join("main.ledger") }
+                    }))
+                    .finish(),
+            )
+            .await?;
+        for _ in 0..3 {
+            let _ = context.recv::<PublishDiagnosticsParams>().await?;
+        }
+
+        let completions = match context.completion(2, 12).await? {
+            Some(CompletionResponse::Array(completions)) => completions,
+            Some(CompletionResponse::List(_)) | None => unreachable!(),
+        };
+        let mut labels = completions
+            .into_iter()
+            .map(|item| item.label)
+            .collect::<Vec<_>>();
+        labels.sort();
+        insta::assert_debug_snapshot!(labels, @r#"
+        [
+            "Assets:Checking",
+            "Assets:Savings",
+        ]
+        "#);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn watch_included_files() -> anyhow::Result<()> {
+        let mut context = TestContext::new().await?;
+        let request = jsonrpc::Request::build("initialize")
+            .id(1)
+            .params(serde_json::json!({
+                "capabilities":{
+                    "workspace": { "didChangeWatchedFiles": { "dynamicRegistration": true } }
+                },
+            }))
+            .finish();
+        let _ = context
+            .request::<lsp_types::InitializeResult>(&request)
+            .await?;
+
+        let watchers = |params: RegistrationParams| {
+            params
+                .registrations
+                .into_iter()
+                .filter_map(|registration| registration.register_options)
+                .filter_map(|options| {
+                    serde_json::from_value::<DidChangeWatchedFilesRegistrationOptions>(options).ok()
+                })
+                .flat_map(|options| options.watchers)
+                .map(|watcher| match watcher.glob_pattern {
+                    GlobPattern::String(pattern) => pattern,
+                    GlobPattern::Relative(pattern) => pattern.pattern,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        context
+            .send(
+                &jsonrpc::Request::build("initialized")
+                    .params(serde_json::json!({}))
+                    .finish(),
+            )
+            .await?;
+        let registered = watchers(context.recv::<RegistrationParams>().await?);
+        context.respond(0).await?;
+        insta::assert_debug_snapshot!(registered, @r#"
+        [
+            "**/*.ledger",
+        ]
+        "#);
+
+        // the included file doesn't exist yet, but it's watched for when it
+        // does
+        context
+            .open_document("file:///foo.ledger", "include bar.ledger\n")
+            .await?;
+        let _ = context.recv::<PublishDiagnosticsParams>().await?;
+        let unregistered = context.recv::<UnregistrationParams>().await?;
+        context.respond(1).await?;
+        let registered = watchers(context.recv::<RegistrationParams>().await?);
+        context.respond(2).await?;
+        insta::assert_debug_snapshot!((unregistered.unregisterations, registered), @r#"
+        (
             [
-                (
-                    "Mark transaction as pending (!)",
-                    [
-                        [
-                            TextEdit {
-                                range: Range {
-                                    start: Position {
-                                        line: 4,
-                                        character: 8,
-                                    },
-                                    end: Position {
-                                        line: 4,
-                                        character: 10,
-                                    },
-                                },
-                                new_text: " !",
-                            },
-                        ],
-                    ],
-                ),
-                (
-                    "Mark transaction as not cleared",
-                    [
-                        [
-                            TextEdit {
-                                range: Range {
-                                    start: Position {
-                                        line: 4,
-                                        character: 8,
-                                    },
-                                    end: Position {
-                                        line: 4,
-                                        character: 10,
-                                    },
-                                },
-                                new_text: "",
-                            },
-                        ],
-                    ],
-                ),
-            ]
-            "#
-        );
+                Unregistration {
+                    id: "watched-files",
+                    method: "workspace/didChangeWatchedFiles",
+                },
+            ],
+            [
+                "**/*.ledger",
+                "/bar.ledger",
+                "/foo.ledger",
+            ],
+        )
+        "#);
 
         Ok(())
     }
 
     #[test_log::test(tokio::test)]
-    async fn code_actions_with_effective_date() -> anyhow::Result<()> {
+    async fn pull_diagnostics() -> anyhow::Result<()> {
         let mut context = TestContext::new().await?;
         context.initialize().await?;
 
         let source = textwrap::dedent(
             "
-            2024/01/02=2025/03/04 Payee1
-                Account
-                Account
+            2024/01/03 Unbalanced
+                Expenses:Food    $10.00
+                Assets:Checking  $-9.50
             ",
         );
         context.prep_document(&source).await?;
+        let _ = context.recv::<PublishDiagnosticsParams>().await?;
 
-        let actions = context.code_action(2, 10).await?.unwrap();
+        let pull = |previous_result_id: Option<String>| {
+            jsonrpc::Request::build("textDocument/diagnostic")
+                .id(4)
+                .params(serde_json::json!({
+                    "textDocument": { "uri": "file:///foo.ledger" },
+                    "previousResultId": previous_result_id,
+                }))
+                .finish()
+        };
 
-        let actions = actions
-            .iter()
-            .map(|a: &CodeActionOrCommand| match a {
-                CodeActionOrCommand::Command(_) => todo!(),
-                CodeActionOrCommand::CodeAction(action) => (
-                    &action.title,
-                    action
-                        .edit
-                        .as_ref()
-                        .unwrap()
-                        .changes
-                        .as_ref()
-                        .unwrap()
-                        .values()
-                        .collect::<Vec<_>>(),
-                ),
-            })
-            .collect::<Vec<_>>();
-        insta::assert_debug_snapshot!(actions,
-            @r#"
+        let result_id = match context
+            .request::<DocumentDiagnosticReportResult>(&pull(None))
+            .await?
+        {
+            DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Full(report)) => {
+                let messages = report
+                    .full_document_diagnostic_report
+                    .items
+                    .iter()
+                    .map(|d| &d.message)
+                    .collect::<Vec<_>>();
+                insta::assert_debug_snapshot!(messages, @r#"
                 [
-                    (
-                        "Mark transaction as pending (!)",
-                        [
-                            [
-                                TextEdit {
-                                    range: Range {
-                                        start: Position {
-                                            line: 1,
-                                            character: 21,
-                                        },
-                                        end: Position {
-                                            line: 1,
-                                            character: 21,
-                                        },
-                                    },
-                                    new_text: " !",
-                                },
-                            ],
-                        ],
-                    ),
-                    (
-                        "Mark transaction as cleared (*)",
-                        [
-                            [
-                                TextEdit {
-                                    range: Range {
-                                        start: Position {
-                                            line: 1,
-                                            character: 21,
-                                        },
-                                        end: Position {
-                                            line: 1,
-                                            character: 21,
-                                        },
-                                    },
-                                    new_text: " *",
-                                },
-                            ],
-                        ],
-                    ),
+                    "Transaction does not balance; unbalanced remainder is $0.50",
                 ]
-                "#
-        );
-
-        Ok(())
-    }
-
-    #[test_log::test(tokio::test)]
-    async fn code_actions_bug_with_maybe_invalid_xacts() -> anyhow::Result<()> {
-        let mut context = TestContext::new().await?;
-        context.initialize().await?;
+                "#);
+                report.full_document_diagnostic_report.result_id
+            }
+            report => anyhow::bail!("expected a full report, got {report:?}"),
+        };
 
-        let source = vec![
-            textwrap::dedent(
-                "
-                24/01/02 Payee1
-                    Account",
-            ),
-            // a line w/ 4 spaces, like we just hit <enter> to add another account
-            "    ".to_string(),
-            // actual blank line between above and below xacts
-            textwrap::dedent(
-                "
-                24/01/03 Payee2
-                    Account2
-                ",
+        let report = context
+            .request::<DocumentDiagnosticReportResult>(&pull(result_id))
+            .await?;
+        insta::assert_debug_snapshot!(
+            matches!(
+                report,
+                DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Unchanged(_))
             ),
-        ]
-        .join("\n");
-        context.prep_document(&source).await?;
-        // ask for actions at pos 3:4 (end of new "empty" account line)
-        let actions = context.code_action(3, 4).await?.unwrap();
-
-        let actions = actions
-            .iter()
-            .map(|a: &CodeActionOrCommand| match a {
-                CodeActionOrCommand::Command(_) => todo!(),
-                CodeActionOrCommand::CodeAction(action) => (
-                    &action.title,
-                    action
-                        .edit
-                        .as_ref()
-                        .unwrap()
-                        .changes
-                        .as_ref()
-                        .unwrap()
-                        .values()
-                        .collect::<Vec<_>>(),
-                ),
-            })
-            .collect::<Vec<_>>();
-        insta::assert_debug_snapshot!(actions,
-            @r#"
-            [
-                (
-                    "Mark transaction as pending (!)",
-                    [
-                        [
-                            TextEdit {
-                                range: Range {
-                                    start: Position {
-                                        line: 1,
-                                        character: 8,
-                                    },
-                                    end: Position {
-                                        line: 1,
-                                        character: 8,
-                                    },
-                                },
-                                new_text: " !",
-                            },
-                        ],
-                    ],
-                ),
-                (
-                    "Mark transaction as cleared (*)",
-                    [
-                        [
-                            TextEdit {
-                                range: Range {
-                                    start: Position {
-                                        line: 1,
-                                        character: 8,
-                                    },
-                                    end: Position {
-                                        line: 1,
-                                        character: 8,
-                                    },
-                                },
-                                new_text: " *",
-                            },
-                        ],
-                    ],
-                ),
-            ]
-            "#
+            @"true"
         );
 
         Ok(())
@@ -1421,6 +3813,14 @@ mod test {
             }
         }
 
+        /// Respond to a request from the server, eg `client/registerCapability`
+        pub async fn respond(&mut self, id: i64) -> anyhow::Result<()> {
+            let response = jsonrpc::Response::from_ok(jsonrpc::Id::Number(id), Value::Null);
+            self.request_tx
+                .send(encode_message(None, &serde_json::to_string(&response)?))?;
+            Ok(())
+        }
+
         pub async fn request<R: std::fmt::Debug + serde::de::DeserializeOwned>(
             &mut self,
             request: &jsonrpc::Request,
@@ -1443,9 +3843,13 @@ mod test {
         }
 
         pub async fn prep_document(&mut self, content: &str) -> anyhow::Result<()> {
+            self.open_document("file:///foo.ledger", content).await
+        }
+
+        pub async fn open_document(&mut self, uri: &str, content: &str) -> anyhow::Result<()> {
             let request = jsonrpc::Request::build("textDocument/didOpen")
                 .params(serde_json::json!({"textDocument":{
-                    "uri": "file:///foo.ledger",
+                    "uri": uri,
                     "text": content,
                     "version": 0,
                     "languageId": "ledger"
@@ -1457,10 +3861,38 @@ mod test {
             Ok(())
         }
 
+        pub async fn change_document(
+            &mut self,
+            version: i32,
+            changes: serde_json::Value,
+        ) -> anyhow::Result<()> {
+            let request = jsonrpc::Request::build("textDocument/didChange")
+                .params(serde_json::json!({
+                    "textDocument":{
+                        "uri": "file:///foo.ledger",
+                        "version": version,
+                    },
+                    "contentChanges": changes,
+                }))
+                .finish();
+
+            self.send(&request).await
+        }
+
         pub async fn code_action(
             &mut self,
             line: u8,
             col: u8,
+        ) -> anyhow::Result<Option<CodeActionResponse>> {
+            self.code_action_with_diagnostics(line, col, serde_json::json!([]))
+                .await
+        }
+
+        pub async fn code_action_with_diagnostics(
+            &mut self,
+            line: u8,
+            col: u8,
+            diagnostics: serde_json::Value,
         ) -> anyhow::Result<Option<CodeActionResponse>> {
             let request = jsonrpc::Request::build("textDocument/codeAction")
                 .id(3)
@@ -1469,7 +3901,7 @@ mod test {
                         "start": { "line": line, "character": col },
                         "end":   { "line": line, "character": col }
                     },
-                    "context": { "diagnostics": [] },
+                    "context": { "diagnostics": diagnostics },
                     "textDocument":{
                         "uri": "file:///foo.ledger",
                         "text": "not used",
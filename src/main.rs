@@ -7,8 +7,14 @@ use anyhow::Result;
 use crate::backend::LedgerBackend;
 
 mod backend;
+mod backend_amount;
+mod backend_diagnostics;
 mod backend_format;
+mod backend_include;
+mod backend_index;
 mod backend_nodes;
+mod backend_position;
+mod backend_semantic_tokens;
 mod lsp;
 
 #[tokio::main]
@@ -29,7 +35,7 @@ async fn main() {
             };
 
             let mut be = LedgerBackend::new();
-            be.parse_document(&source);
+            be.parse_document(&file, &source);
 
             let mut visited = HashSet::new();
             #[allow(clippy::unwrap_used)]